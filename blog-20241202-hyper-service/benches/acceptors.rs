@@ -0,0 +1,75 @@
+use blog_20241202_hyper_service::config::SharedConfig;
+use blog_20241202_hyper_service::good_service::GoodTowerService;
+use blog_20241202_hyper_service::io_stats::ServerStats;
+use blog_20241202_hyper_service::server::{accept_loop, bind_reuseport, AcceptorRuntime, Server};
+use blog_20241202_hyper_service::supervisor::ConnectionSupervisor;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+/// How many clients connect at the same time in each iteration
+const STORM_SIZE: usize = 200;
+/// How many listeners the multi-acceptor variants use
+const ACCEPTORS: usize = 4;
+
+pub fn benchmarks(_c: &mut Criterion) {
+    let mut c = Criterion::default()
+        .measurement_time(std::time::Duration::from_secs(20));
+    let mut group = c.benchmark_group("Connection storm acceptor approaches");
+    let server_rt = Runtime::new().unwrap();
+    let client_rt = Runtime::new().unwrap();
+
+    let single_addr = server_rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_loop(listener, GoodTowerService::new(), Arc::new(ServerStats::default()), ConnectionSupervisor::start(), SharedConfig::default()));
+        addr
+    });
+    group.bench_function("Single Acceptor", |b| {
+        b.to_async(&client_rt).iter(|| connection_storm(single_addr));
+    });
+
+    let (shared_addr, _shared_server) = server_rt.block_on(async {
+        let listeners = bind_reuseport("127.0.0.1:0".parse().unwrap(), ACCEPTORS).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let mut server = Server::start();
+        server.serve_acceptors(listeners, AcceptorRuntime::Shared, GoodTowerService::new()).unwrap();
+        (addr, server)
+    });
+    group.bench_function("SO_REUSEPORT Acceptors Shared Runtime", |b| {
+        b.to_async(&client_rt).iter(|| connection_storm(shared_addr));
+    });
+
+    let listeners = bind_reuseport("127.0.0.1:0".parse().unwrap(), ACCEPTORS).unwrap();
+    let per_acceptor_addr = listeners[0].local_addr().unwrap();
+    // The supervisor lives on the server runtime, the connections on the runtime of each acceptor
+    let mut per_acceptor_server = server_rt.block_on(async { Server::start() });
+    per_acceptor_server.serve_acceptors(listeners, AcceptorRuntime::PerAcceptor, GoodTowerService::new()).unwrap();
+    group.bench_function("SO_REUSEPORT Acceptors Runtime Per Acceptor", |b| {
+        b.to_async(&client_rt).iter(|| connection_storm(per_acceptor_addr));
+    });
+
+    group.finish()
+}
+
+/// Opens many connections at once, each making a single request
+async fn connection_storm(addr: SocketAddr) {
+    let clients: Vec<_> = (0..STORM_SIZE)
+        .map(|_| tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n").await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        }))
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
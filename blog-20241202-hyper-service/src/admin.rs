@@ -0,0 +1,68 @@
+use crate::error::MyError;
+use crate::io_stats::ServerStats;
+use crate::request_id::RequestId;
+use crate::supervisor::ConnectionSupervisor;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Operational routes, meant for a listener that only operators can reach.
+/// `GET /stats` reports the traffic totals of the whole server, across every listener.
+#[derive(Clone)]
+pub struct AdminService {
+    server_stats: Arc<ServerStats>,
+    supervisor: ConnectionSupervisor,
+}
+
+impl AdminService {
+    pub fn new(server_stats: Arc<ServerStats>, supervisor: ConnectionSupervisor) -> Self {
+        AdminService { server_stats, supervisor }
+    }
+
+    fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            live_connections: self.supervisor.live_connections(),
+            connections_opened: self.server_stats.connections_opened.load(Ordering::Relaxed),
+            connections_closed: self.server_stats.connections_closed.load(Ordering::Relaxed),
+            bytes_read: self.server_stats.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.server_stats.bytes_written.load(Ordering::Relaxed),
+            requests: self.server_stats.requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The body of `GET /stats`
+#[derive(Serialize, Debug)]
+struct StatsSnapshot {
+    live_connections: usize,
+    connections_opened: u64,
+    connections_closed: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    requests: u64,
+}
+
+impl<BODY> hyper::service::Service<Request<BODY>> for AdminService {
+    type Response = Response<String>;
+    type Error = MyError;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<BODY>) -> Self::Future {
+        let request_id = req.extensions().get::<RequestId>().cloned();
+        let result = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/stats") => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&self.snapshot()).unwrap())
+                .unwrap()),
+            (method, "/stats") => Err(MyError::MethodNotAllowed(method.clone())),
+            (_, path) => Err(MyError::NotFound(path.to_string())),
+        };
+        let response = result.unwrap_or_else(|e| e.into_response(request_id.as_ref()));
+        Box::pin(async move { Ok(response) })
+    }
+}
@@ -2,7 +2,6 @@ use crate::error::MyError;
 use futures::future::BoxFuture;
 use hyper::body::Incoming;
 use hyper::{Method, Request, Response};
-use std::fmt::Display;
 
 /// An example of a bad tower-esque service that cannot be tested since it uses Incoming and that cannot be constructed directly
 pub struct BadTowerService {}
@@ -21,10 +20,10 @@ impl hyper::service::Service<Request<Incoming>> for BadTowerService {
                 }
                 Method::POST => {
                     println!("POST request");
-                    Err(MyError { payload: "POST is not allowed".to_string() })
+                    Err(MyError::MethodNotAllowed(Method::POST))
                 }
-                _ => {
-                    Err(MyError { payload: "Method not allowed".to_string() })
+                method => {
+                    Err(MyError::MethodNotAllowed(method))
                 }
             }
         })
@@ -0,0 +1,145 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{ALLOW, CONTENT_TYPE, HOST};
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// A request that is sent, unchanged, to every service being compared
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub name: &'static str,
+    pub method: Method,
+    pub path: &'static str,
+    pub headers: &'static [(&'static str, &'static str)],
+    pub body: &'static [u8],
+}
+
+/// The requests the services are compared on: the happy paths, and the ways a request can go wrong
+pub fn scenarios() -> Vec<Scenario> {
+    let scenario = |name, method, path, headers, body| Scenario { name, method, path, headers, body };
+    vec![
+        scenario("GET /", Method::GET, "/", &[], b""),
+        scenario("POST text is echoed", Method::POST, "/", &[("content-type", "text/plain")], b"hello"),
+        scenario("POST without a body", Method::POST, "/", &[], b""),
+        scenario("POST invalid UTF-8", Method::POST, "/", &[("content-type", "text/plain")], b"\xff\xfe"),
+        scenario("POST unsupported content type", Method::POST, "/", &[("content-type", "image/png")], b"png"),
+        scenario("PUT / is not allowed", Method::PUT, "/", &[], b""),
+        scenario("DELETE / is not allowed", Method::DELETE, "/", &[], b""),
+        scenario("HEAD /", Method::HEAD, "/", &[], b""),
+        scenario("OPTIONS /", Method::OPTIONS, "/", &[], b""),
+        scenario("GET a missing key", Method::GET, "/kv/missing", &[], b""),
+        scenario("PUT a key", Method::PUT, "/kv/a", &[("content-type", "text/plain")], b"value"),
+    ]
+}
+
+/// What a service did with a scenario.
+/// Only the parts of the response that say something about the behavior are kept, so responses
+/// can be compared without the `Date` header getting in the way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Observed {
+    Response {
+        status: u16,
+        content_type: Option<String>,
+        allow: Option<String>,
+        body: String,
+    },
+    /// The connection ended without a response, which is how hyper surfaces a service error
+    NoResponse(String),
+}
+
+impl Display for Observed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Observed::Response { status, content_type, allow, body } => {
+                write!(f, "{status}")?;
+                if let Some(content_type) = content_type {
+                    write!(f, " content-type={content_type}")?;
+                }
+                if let Some(allow) = allow {
+                    write!(f, " allow={allow}")?;
+                }
+                write!(f, " body={body:?}")
+            }
+            Observed::NoResponse(reason) => write!(f, "no response ({reason})"),
+        }
+    }
+}
+
+/// Sends the scenario to the server at `addr` on a fresh connection
+pub async fn observe(addr: SocketAddr, scenario: &Scenario) -> Observed {
+    let stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(e) => return Observed::NoResponse(e.to_string()),
+    };
+    let (mut sender, connection) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+        Ok(handshake) => handshake,
+        Err(e) => return Observed::NoResponse(e.to_string()),
+    };
+    tokio::spawn(connection);
+
+    let mut request = Request::builder()
+        .method(scenario.method.clone())
+        .uri(scenario.path)
+        .header(HOST, addr.to_string());
+    for (name, value) in scenario.headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(Full::new(Bytes::from_static(scenario.body))).unwrap();
+    let response = match sender.send_request(request).await {
+        Ok(response) => response,
+        Err(e) => return Observed::NoResponse(e.to_string()),
+    };
+    let header = |name| response.headers().get(name).map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+    let status = response.status().as_u16();
+    let content_type = header(CONTENT_TYPE);
+    let allow = header(ALLOW);
+    match response.into_body().collect().await {
+        Ok(body) => Observed::Response {
+            status,
+            content_type,
+            allow,
+            body: String::from_utf8_lossy(&body.to_bytes()).into_owned(),
+        },
+        Err(e) => Observed::NoResponse(e.to_string()),
+    }
+}
+
+/// A scenario the two services handled differently
+#[derive(Clone, Debug)]
+pub struct Difference {
+    pub scenario: &'static str,
+    pub bad: Observed,
+    pub good: Observed,
+}
+
+/// Runs every scenario against both servers, keeping the ones where they disagree
+pub async fn compare(bad: SocketAddr, good: SocketAddr) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    for scenario in scenarios() {
+        let bad = observe(bad, &scenario).await;
+        let good = observe(good, &scenario).await;
+        if bad != good {
+            differences.push(Difference { scenario: scenario.name, bad, good });
+        }
+    }
+    differences
+}
+
+/// The differences, one scenario per paragraph
+pub struct Report(pub Vec<Difference>);
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} of {} scenarios behave differently", self.0.len(), scenarios().len())?;
+        for difference in &self.0 {
+            writeln!(f)?;
+            writeln!(f, "{}", difference.scenario)?;
+            writeln!(f, "  bad:  {}", difference.bad)?;
+            writeln!(f, "  good: {}", difference.good)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use crate::rate_limit::RateLimiter;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tracing::level_filters::LevelFilter;
+
+/// The settings that can be changed while the server is running, read from a JSON file such as
+/// `{"body_read_timeout_ms": 5000, "max_connections": 512, "rate_limit": {"requests_per_second": 100, "burst": 20}}`.
+/// Missing fields take their default value.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// How long a client gets to send the request line and headers
+    pub header_read_timeout_ms: u64,
+    /// How long a client gets to send the full request body
+    pub body_read_timeout_ms: u64,
+    /// Connections beyond this many are closed as soon as they are accepted
+    pub max_connections: Option<usize>,
+    /// Requests beyond this rate, across every connection, are answered with 429
+    pub rate_limit: Option<RateLimitConfig>,
+    pub log_level: LogLevel,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            header_read_timeout_ms: 30_000,
+            body_read_timeout_ms: 30_000,
+            max_connections: None,
+            rate_limit: None,
+            log_level: LogLevel::Info,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    /// How many requests may arrive at once after a quiet period
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Why a config was not loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(std::io::Error),
+    Parse(serde_json::Error),
+    /// The config parsed, but one of its values makes no sense
+    Invalid(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(e) => write!(f, "failed to read the config: {e}"),
+            ConfigError::Parse(e) => write!(f, "failed to parse the config: {e}"),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(ConfigError::Read)?)
+    }
+
+    pub fn parse(json: &str) -> Result<Self, ConfigError> {
+        let config: ServerConfig = serde_json::from_str(json).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.header_read_timeout_ms == 0 || self.body_read_timeout_ms == 0 {
+            return Err(ConfigError::Invalid("timeouts must be greater than zero"));
+        }
+        if self.max_connections == Some(0) {
+            return Err(ConfigError::Invalid("max_connections must be greater than zero"));
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if !(rate_limit.requests_per_second.is_finite() && rate_limit.requests_per_second > 0.0) {
+                return Err(ConfigError::Invalid("rate_limit.requests_per_second must be a positive number"));
+            }
+            if rate_limit.burst == 0 {
+                return Err(ConfigError::Invalid("rate_limit.burst must be greater than zero"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A config in effect, together with the state built from it.
+/// Every connection holds on to the one that was current when it was accepted, so a reload only
+/// affects connections accepted after it.
+#[derive(Debug)]
+pub struct ActiveConfig {
+    config: ServerConfig,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl ActiveConfig {
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn header_read_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.header_read_timeout_ms)
+    }
+
+    pub fn body_read_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.body_read_timeout_ms)
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_deref()
+    }
+}
+
+/// The body read timeout of the connection a request arrived on, found in the request extensions
+#[derive(Clone, Copy, Debug)]
+pub struct BodyReadTimeout(pub Duration);
+
+/// The current config of a server, shared by every accept loop and replaced on reload
+#[derive(Clone)]
+pub struct SharedConfig {
+    current: Arc<watch::Sender<Arc<ActiveConfig>>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let rate_limiter = new_rate_limiter(&config);
+        let (current, _) = watch::channel(Arc::new(ActiveConfig { config, rate_limiter }));
+        Ok(SharedConfig { current: Arc::new(current) })
+    }
+
+    /// The config new connections should use
+    pub fn current(&self) -> Arc<ActiveConfig> {
+        self.current.borrow().clone()
+    }
+
+    /// Notified whenever the config is replaced
+    pub fn subscribe(&self) -> watch::Receiver<Arc<ActiveConfig>> {
+        self.current.subscribe()
+    }
+
+    /// Makes `config` the current config, unless it is invalid, in which case the current one stays.
+    /// Returns whether anything changed.
+    pub fn replace(&self, config: ServerConfig) -> Result<bool, ConfigError> {
+        config.validate()?;
+        let current = self.current();
+        if current.config == config {
+            return Ok(false);
+        }
+        // Reloading an unrelated setting should not hand every client a fresh burst
+        let rate_limiter = if current.config.rate_limit == config.rate_limit {
+            current.rate_limiter.clone()
+        } else {
+            new_rate_limiter(&config)
+        };
+        self.current.send_replace(Arc::new(ActiveConfig { config, rate_limiter }));
+        Ok(true)
+    }
+}
+
+impl Default for SharedConfig {
+    fn default() -> Self {
+        // The default config is always valid
+        SharedConfig::new(ServerConfig::default()).unwrap()
+    }
+}
+
+fn new_rate_limiter(config: &ServerConfig) -> Option<Arc<RateLimiter>> {
+    config.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit.requests_per_second, limit.burst)))
+}
+
+/// Reloads the config from `path` whenever the process receives SIGHUP, and whenever the
+/// modification time of the file changes, checking every `poll_interval`.
+/// A config that fails to load is logged and ignored.
+/// If SIGHUP cannot be listened for, that is logged too, and only the file is watched.
+pub async fn watch(path: PathBuf, config: SharedConfig, poll_interval: Duration) {
+    let mut hangup = signal(SignalKind::hangup())
+        .inspect_err(|e| tracing::error!("Not reloading on SIGHUP, failed to listen for it: {e}"))
+        .ok();
+    let mut modified = modified_at(&path);
+    loop {
+        tokio::select! {
+            _ = hangup_received(&mut hangup) => tracing::info!("Received SIGHUP, reloading {}", path.display()),
+            _ = tokio::time::sleep(poll_interval) => {
+                if modified_at(&path) == modified {
+                    continue;
+                }
+                tracing::info!("{} changed, reloading", path.display());
+            }
+        }
+        modified = modified_at(&path);
+        match ServerConfig::load(&path).and_then(|new_config| config.replace(new_config)) {
+            Ok(true) => tracing::info!("Applied new config from {}", path.display()),
+            Ok(false) => tracing::info!("Config in {} is unchanged", path.display()),
+            Err(e) => tracing::error!("Keeping the previous config, {e}"),
+        }
+    }
+}
+
+/// Resolves on the next SIGHUP, or never if there is no way to receive it
+async fn hangup_received(hangup: &mut Option<Signal>) {
+    match hangup {
+        Some(hangup) => {
+            hangup.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfigError, LogLevel, ServerConfig, SharedConfig};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let config = ServerConfig::parse(r#"{"max_connections": 10, "log_level": "debug", "rate_limit": {"requests_per_second": 5, "burst": 2}}"#).unwrap();
+        assert_eq!(config.max_connections, Some(10));
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.body_read_timeout_ms, ServerConfig::default().body_read_timeout_ms);
+
+        assert!(matches!(ServerConfig::parse("{"), Err(ConfigError::Parse(_))));
+        assert!(matches!(ServerConfig::parse(r#"{"max_conections": 10}"#), Err(ConfigError::Parse(_))));
+        assert!(matches!(ServerConfig::parse(r#"{"log_level": "loud"}"#), Err(ConfigError::Parse(_))));
+        assert!(matches!(ServerConfig::parse(r#"{"body_read_timeout_ms": 0}"#), Err(ConfigError::Invalid(_))));
+        assert!(matches!(ServerConfig::parse(r#"{"rate_limit": {"requests_per_second": -1, "burst": 1}}"#), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_replace_keeps_old_snapshots_and_rejects_invalid_configs() {
+        let shared = SharedConfig::default();
+        let before = shared.current();
+
+        let changed = ServerConfig { max_connections: Some(1), ..Default::default() };
+        assert!(shared.replace(changed.clone()).unwrap());
+        assert!(!shared.replace(changed.clone()).unwrap());
+        // Whoever took the config before the reload keeps seeing the old one
+        assert_eq!(before.config().max_connections, None);
+        assert_eq!(shared.current().config(), &changed);
+
+        let invalid = ServerConfig { header_read_timeout_ms: 0, ..Default::default() };
+        assert!(shared.replace(invalid).is_err());
+        assert_eq!(shared.current().config(), &changed);
+    }
+
+    #[test]
+    fn test_rate_limiter_survives_unrelated_changes() {
+        let limited = ServerConfig::parse(r#"{"rate_limit": {"requests_per_second": 1, "burst": 1}}"#).unwrap();
+        let shared = SharedConfig::new(limited.clone()).unwrap();
+        let limiter = shared.current().rate_limiter.clone().unwrap();
+
+        shared.replace(ServerConfig { log_level: LogLevel::Trace, ..limited }).unwrap();
+        assert!(Arc::ptr_eq(&limiter, shared.current().rate_limiter.as_ref().unwrap()));
+        shared.replace(ServerConfig::default()).unwrap();
+        assert!(shared.current().rate_limiter().is_none());
+    }
+}
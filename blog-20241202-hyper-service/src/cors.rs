@@ -0,0 +1,105 @@
+use hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{HeaderMap, Method, Response};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// What a single origin is allowed to do
+#[derive(Clone, Debug, Default)]
+pub struct CorsPolicy {
+    /// The methods this origin may use. `None` allows every method the resource supports.
+    pub allowed_methods: Option<Vec<Method>>,
+    /// Request headers this origin may send beyond the CORS-safelisted ones
+    pub allowed_headers: Vec<HeaderName>,
+    /// How long browsers may cache the preflight result
+    pub max_age: Option<Duration>,
+    pub allow_credentials: bool,
+}
+
+/// CORS configuration, keyed by origin (for example `https://example.com`).
+/// Origins that are not configured get no CORS headers at all, so browsers will block them.
+#[derive(Clone, Debug, Default)]
+pub struct CorsConfig {
+    origins: HashMap<String, CorsPolicy>,
+}
+
+impl CorsConfig {
+    pub fn allow_origin(mut self, origin: impl Into<String>, policy: CorsPolicy) -> Self {
+        self.origins.insert(origin.into(), policy);
+        self
+    }
+
+    /// The request's origin header together with its policy, if the origin is configured
+    fn policy<'a>(&self, headers: &'a HeaderMap) -> Option<(&'a HeaderValue, &CorsPolicy)> {
+        let origin = headers.get(ORIGIN)?;
+        let policy = self.origins.get(origin.to_str().ok()?)?;
+        Some((origin, policy))
+    }
+
+    /// Whether the request is a CORS preflight rather than a plain OPTIONS request
+    pub fn is_preflight(headers: &HeaderMap) -> bool {
+        headers.contains_key(ORIGIN) && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Adds the preflight headers to `response`, if the origin, the requested method and the
+    /// requested headers are all allowed. Otherwise the response is left alone.
+    pub fn apply_preflight(&self, request_headers: &HeaderMap, supported_methods: &[Method], response: &mut Response<String>) {
+        let Some((origin, policy)) = self.policy(request_headers) else {
+            return;
+        };
+        let allowed_methods: Vec<&Method> = supported_methods.iter()
+            .filter(|method| policy.allowed_methods.as_ref().is_none_or(|allowed| allowed.contains(method)))
+            .collect();
+        let requested_method = request_headers.get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+        if !requested_method.is_some_and(|method| allowed_methods.contains(&&method)) {
+            return;
+        }
+        let requested_headers = request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .map(|headers| String::from_utf8_lossy(headers.as_bytes()).into_owned())
+            .unwrap_or_default();
+        let headers_allowed = requested_headers.split(',')
+            .map(|header| header.trim())
+            .filter(|header| !header.is_empty())
+            .all(|header| policy.allowed_headers.iter().any(|allowed| allowed.as_str().eq_ignore_ascii_case(header)));
+        if !headers_allowed {
+            return;
+        }
+
+        let headers = response.headers_mut();
+        apply_origin(origin.clone(), policy, headers);
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, join(allowed_methods.iter().map(|method| method.as_str())));
+        if !policy.allowed_headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, join(policy.allowed_headers.iter().map(|header| header.as_str())));
+        }
+        if let Some(max_age) = policy.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+    }
+
+    /// Adds the headers that let an allowed origin read an actual (non-preflight) response
+    pub fn apply_actual(&self, request_headers: &HeaderMap, response: &mut Response<String>) {
+        if let Some((origin, policy)) = self.policy(request_headers) {
+            apply_origin(origin.clone(), policy, response.headers_mut());
+        }
+    }
+}
+
+fn apply_origin(origin: HeaderValue, policy: &CorsPolicy, headers: &mut HeaderMap) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    // The response depends on the origin, so caches must not share it between origins
+    headers.append(VARY, HeaderValue::from_static("origin"));
+    if policy.allow_credentials {
+        headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+}
+
+/// Joins header tokens into a single comma separated header value
+pub fn join<'a>(tokens: impl Iterator<Item=&'a str>) -> HeaderValue {
+    let joined = tokens.collect::<Vec<_>>().join(", ");
+    // Methods and header names are tokens, which are always valid header values
+    HeaderValue::from_str(&joined).unwrap()
+}
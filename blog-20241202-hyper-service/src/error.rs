@@ -1,16 +1,131 @@
+use crate::request_id::RequestId;
+use crate::storage::PreconditionFailed;
+use hyper::{Method, Response, StatusCode};
 use std::fmt::{Display, Formatter};
+use std::string::FromUtf8Error;
+use tokio::time::error::Elapsed;
 
+/// The errors our services can produce.
+/// Every variant that wraps an underlying failure keeps it, so it is reachable via `source()`
 #[derive(Debug)]
-pub struct MyError {
-    pub payload: String,
+pub enum MyError {
+    /// The request used a method that the service does not handle
+    MethodNotAllowed(Method),
+    /// Reading the request body failed, for example because the connection dropped
+    BodyRead(Box<dyn std::error::Error + Send + Sync>),
+    /// The request body was read, but its contents were not what we expected
+    Decode(FromUtf8Error),
+    /// The operation did not complete in time
+    Timeout(Elapsed),
+    /// The request body is, or announced it would be, larger than the service accepts
+    PayloadTooLarge { limit: u64 },
+    /// The request body has a content type the service does not handle
+    UnsupportedMediaType(String),
+    /// Nothing exists at the requested path
+    NotFound(String),
+    /// An `If-Match` or `If-None-Match` condition did not hold
+    PreconditionFailed,
+    /// A multipart body could not be parsed, for example because its boundary is missing or broken
+    Multipart(multer::Error),
+    /// The client is sending requests faster than the configured rate limit allows
+    TooManyRequests,
 }
 
-impl MyError {}
+impl MyError {
+    /// A stable, machine-readable code for this error, safe to use in responses and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            MyError::MethodNotAllowed(_) => "method_not_allowed",
+            MyError::BodyRead(_) => "body_read",
+            MyError::Decode(_) => "decode",
+            MyError::Timeout(_) => "timeout",
+            MyError::PayloadTooLarge { .. } => "payload_too_large",
+            MyError::UnsupportedMediaType(_) => "unsupported_media_type",
+            MyError::NotFound(_) => "not_found",
+            MyError::PreconditionFailed => "precondition_failed",
+            MyError::Multipart(_) => "malformed_multipart",
+            MyError::TooManyRequests => "too_many_requests",
+        }
+    }
+
+    /// The HTTP status that best describes this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MyError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            MyError::BodyRead(_) | MyError::Decode(_) | MyError::Multipart(_) => StatusCode::BAD_REQUEST,
+            MyError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            MyError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            MyError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MyError::NotFound(_) => StatusCode::NOT_FOUND,
+            MyError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            MyError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Converts the error into a response that a client can act on, with a JSON body containing
+    /// the error code, a human-readable message and, if known, the id of the failed request
+    pub fn into_response(self, request_id: Option<&RequestId>) -> Response<String> {
+        let mut body = serde_json::json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(request_id) = request_id {
+            body["request_id"] = request_id.as_str().into();
+        }
+        let mut response = Response::new(body.to_string());
+        *response.status_mut() = self.status();
+        response
+    }
+}
 
 impl Display for MyError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("Error: {:?}", self))
+        match self {
+            MyError::MethodNotAllowed(method) => write!(f, "method {method} is not allowed"),
+            MyError::BodyRead(_) => write!(f, "failed to read the request body"),
+            MyError::Decode(_) => write!(f, "request body is not valid UTF-8"),
+            MyError::Timeout(_) => write!(f, "request timed out"),
+            MyError::PayloadTooLarge { limit } => write!(f, "request body is larger than {limit} bytes"),
+            MyError::UnsupportedMediaType(content_type) => write!(f, "content type {content_type} is not supported"),
+            MyError::NotFound(path) => write!(f, "{path} was not found"),
+            MyError::PreconditionFailed => write!(f, "precondition failed"),
+            MyError::Multipart(_) => write!(f, "malformed multipart body"),
+            MyError::TooManyRequests => write!(f, "too many requests, slow down"),
+        }
+    }
+}
+
+impl std::error::Error for MyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MyError::MethodNotAllowed(_)
+            | MyError::PayloadTooLarge { .. }
+            | MyError::UnsupportedMediaType(_)
+            | MyError::NotFound(_)
+            | MyError::PreconditionFailed
+            | MyError::TooManyRequests => None,
+            MyError::BodyRead(e) => Some(e.as_ref()),
+            MyError::Decode(e) => Some(e),
+            MyError::Timeout(e) => Some(e),
+            MyError::Multipart(e) => Some(e),
+        }
     }
 }
 
-impl std::error::Error for MyError {}
+impl From<FromUtf8Error> for MyError {
+    fn from(e: FromUtf8Error) -> Self {
+        MyError::Decode(e)
+    }
+}
+
+impl From<PreconditionFailed> for MyError {
+    fn from(_: PreconditionFailed) -> Self {
+        MyError::PreconditionFailed
+    }
+}
+
+impl From<Elapsed> for MyError {
+    fn from(e: Elapsed) -> Self {
+        MyError::Timeout(e)
+    }
+}
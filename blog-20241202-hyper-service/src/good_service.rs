@@ -1,57 +1,337 @@
+use crate::config::BodyReadTimeout;
+use crate::cors;
+use crate::cors::CorsConfig;
 use crate::error::MyError;
+use crate::multipart;
+use crate::multipart::MultipartLimits;
+use crate::request_id::RequestId;
+use crate::storage::{KvStore, MemoryStore, Precondition};
 use bytes::Bytes;
-use http_body_util::BodyExt;
-use hyper::{Method, Request, Response};
-use std::fmt::Debug;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use hyper::http::request::Parts;
+use hyper::{Method, Request, Response, StatusCode};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
 
-/// An example of a good tower-esque service that can be tested
-#[derive(Clone)]
-pub struct GoodTowerService {}
+/// How long we are willing to wait for a client to send the full request body, unless the
+/// connection's config says otherwise
+const BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest request body we will read
+pub const MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// The content types we are willing to read. A request without a content type is also accepted.
+const ACCEPTED_CONTENT_TYPES: &[&str] = &["text/plain", "application/json", "application/x-www-form-urlencoded"];
 
-impl<BODY> hyper::service::Service<Request<BODY>> for GoodTowerService
+/// Keys of the key-value resource live under this path
+const KV_PREFIX: &str = "/kv/";
+
+/// An example of a good tower-esque service that can be tested.
+/// Besides echoing POST bodies and summarizing multipart uploads, it serves a key-value resource at `/kv/{key}` backed by `STORE`.
+/// HEAD and OPTIONS are answered for every path from the methods registered for it.
+pub struct GoodTowerService<STORE = MemoryStore> {
+    store: Arc<STORE>,
+    cors: Arc<CorsConfig>,
+}
+
+impl GoodTowerService {
+    /// A service backed by an empty in-memory store
+    pub fn new() -> Self {
+        GoodTowerService::with_store(MemoryStore::default())
+    }
+}
+
+impl Default for GoodTowerService {
+    fn default() -> Self {
+        GoodTowerService::new()
+    }
+}
+
+impl<STORE> GoodTowerService<STORE> {
+    pub fn with_store(store: STORE) -> Self {
+        GoodTowerService { store: Arc::new(store), cors: Arc::new(CorsConfig::default()) }
+    }
+
+    /// Answers CORS preflight requests, and decorates responses, for the configured origins
+    pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+        self.cors = Arc::new(cors);
+        self
+    }
+}
+
+// Clones share the store, which is what lets every connection see the same data
+impl<STORE> Clone for GoodTowerService<STORE> {
+    fn clone(&self) -> Self {
+        GoodTowerService { store: self.store.clone(), cors: self.cors.clone() }
+    }
+}
+
+impl<BODY, STORE> hyper::service::Service<Request<BODY>> for GoodTowerService<STORE>
 where
     BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
     BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    STORE: KvStore,
 {
     type Response = Response<String>;
     type Error = MyError;
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<BODY>) -> Self::Future {
+        let store = self.store.clone();
+        let cors = self.cors.clone();
         Box::pin(async move {
-            let (parts, body) = req.into_parts();
-            match parts.method {
-                Method::GET => {
-                    Ok(Response::new("test".to_string()))
-                }
-                Method::POST => {
-                    match body.collect().await {
-                        Ok(the_body) => {
-                            Ok(Response::new(String::from_utf8(the_body.to_bytes().to_vec()).unwrap()))
-                        }
-                        Err(e) => {
-                            Err(MyError { payload: "unexpected body error".to_string() })
-                        }
+            let request_headers = req.headers().clone();
+            let registered = registered_methods(req.uri().path());
+            let is_head = req.method() == Method::HEAD;
+            let request_id = req.extensions().get::<RequestId>().cloned();
+            let mut response = match handle(req, registered, store.as_ref(), &cors).await {
+                Ok(response) => response,
+                Err(e) => {
+                    // Errors are turned into responses, so the client knows what went wrong
+                    // and the connection can be kept alive
+                    match &request_id {
+                        Some(request_id) => tracing::warn!("Request {request_id} failed [{}]: {}", e.code(), e),
+                        None => tracing::warn!("Request failed [{}]: {}", e.code(), e),
                     }
+                    let method_not_allowed = matches!(e, MyError::MethodNotAllowed(_));
+                    let mut response = e.into_response(request_id.as_ref());
+                    if method_not_allowed {
+                        response.headers_mut().insert(ALLOW, allow_header(registered));
+                    }
+                    response
                 }
-                _ => {
-                    return Err(MyError { payload: "Method not allowed".to_string() })
-                }
+            };
+            if is_head {
+                strip_body(&mut response);
             }
+            if !CorsConfig::is_preflight(&request_headers) {
+                cors.apply_actual(&request_headers, &mut response);
+            }
+            Ok(response)
         })
     }
 }
 
+/// The methods each path handles itself. HEAD and OPTIONS are derived from these.
+fn registered_methods(path: &str) -> &'static [Method] {
+    if path.starts_with(KV_PREFIX) {
+        &[Method::GET, Method::PUT, Method::DELETE]
+    } else {
+        &[Method::GET, Method::POST]
+    }
+}
+
+/// Everything a path supports, including the methods we answer automatically
+fn supported_methods(registered: &[Method]) -> Vec<Method> {
+    let mut methods = registered.to_vec();
+    if registered.contains(&Method::GET) {
+        methods.push(Method::HEAD);
+    }
+    methods.push(Method::OPTIONS);
+    methods
+}
+
+fn allow_header(registered: &[Method]) -> HeaderValue {
+    cors::join(supported_methods(registered).iter().map(|method| method.as_str()))
+}
+
+/// A HEAD response is the GET response without its body, but with the length the body would have had
+fn strip_body(response: &mut Response<String>) {
+    let length = response.body().len();
+    response.headers_mut().entry(CONTENT_LENGTH).or_insert_with(|| HeaderValue::from(length));
+    response.body_mut().clear();
+}
+
+async fn handle<BODY, STORE>(req: Request<BODY>, registered: &[Method], store: &STORE, cors: &CorsConfig) -> Result<Response<String>, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    STORE: KvStore,
+{
+    match *req.method() {
+        Method::OPTIONS => {
+            let mut response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(ALLOW, allow_header(registered))
+                .body(String::new())
+                .unwrap();
+            if CorsConfig::is_preflight(req.headers()) {
+                cors.apply_preflight(req.headers(), &supported_methods(registered), &mut response);
+            }
+            Ok(response)
+        }
+        Method::HEAD if registered.contains(&Method::GET) => {
+            // The GET handler answers, and the body is stripped once we have the response
+            let (mut parts, body) = req.into_parts();
+            parts.method = Method::GET;
+            route(Request::from_parts(parts, body), store).await
+        }
+        _ => route(req, store).await,
+    }
+}
+
+async fn route<BODY, STORE>(req: Request<BODY>, store: &STORE) -> Result<Response<String>, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    STORE: KvStore,
+{
+    let (parts, body) = req.into_parts();
+    if let Some(key) = parts.uri.path().strip_prefix(KV_PREFIX) {
+        let key = key.to_string();
+        return handle_kv(parts, body, &key, store).await;
+    }
+    match parts.method {
+        Method::GET => {
+            Ok(Response::new("test".to_string()))
+        }
+        Method::POST if multipart::is_multipart(&parts) => {
+            timeout(body_read_timeout(&parts), multipart::summarize(&parts, body, &MultipartLimits::default())).await?
+        }
+        Method::POST => {
+            Ok(Response::new(read_body(&parts, body).await?))
+        }
+        method => {
+            Err(MyError::MethodNotAllowed(method))
+        }
+    }
+}
+
+/// GET, PUT and DELETE on a single key, honouring `If-Match` and `If-None-Match`
+async fn handle_kv<BODY, STORE>(parts: Parts, body: BODY, key: &str, store: &STORE) -> Result<Response<String>, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    STORE: KvStore,
+{
+    if key.is_empty() {
+        return Err(MyError::NotFound(parts.uri.path().to_string()));
+    }
+    let precondition = Precondition::from_headers(&parts.headers);
+    match parts.method {
+        Method::GET => {
+            let stored = store.get(key).await;
+            if !precondition.if_match_holds(stored.as_ref().map(|stored| stored.etag.as_str())) {
+                return Err(MyError::PreconditionFailed);
+            }
+            let stored = stored.ok_or_else(|| MyError::NotFound(parts.uri.path().to_string()))?;
+            let status = if precondition.if_none_match_holds(Some(&stored.etag)) { StatusCode::OK } else { StatusCode::NOT_MODIFIED };
+            let body = if status == StatusCode::OK { stored.value } else { String::new() };
+            Ok(Response::builder()
+                .status(status)
+                .header(ETAG, stored.etag)
+                .body(body)
+                .unwrap())
+        }
+        Method::PUT => {
+            // The precondition is checked again by the store, but checking here first means a
+            // failed precondition is reported without reading the body
+            let current = store.get(key).await;
+            if !precondition.holds(current.as_ref().map(|stored| stored.etag.as_str())) {
+                return Err(MyError::PreconditionFailed);
+            }
+            let value = read_body(&parts, body).await?;
+            let outcome = store.put(key, value, &precondition).await?;
+            let status = if outcome.created { StatusCode::CREATED } else { StatusCode::NO_CONTENT };
+            Ok(Response::builder()
+                .status(status)
+                .header(ETAG, outcome.etag)
+                .body(String::new())
+                .unwrap())
+        }
+        Method::DELETE => {
+            match store.delete(key, &precondition).await? {
+                Some(_) => Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(String::new())
+                    .unwrap()),
+                None => Err(MyError::NotFound(parts.uri.path().to_string())),
+            }
+        }
+        method => {
+            Err(MyError::MethodNotAllowed(method))
+        }
+    }
+}
+
+/// Reads a request body as text, enforcing the size, content type and time limits.
+/// Everything we can reject based on the headers is rejected before touching the body.
+/// Hyper only sends `100 Continue` to clients that asked for it once the body is polled,
+/// so a rejected client never has to send the body at all.
+async fn read_body<BODY>(parts: &Parts, body: BODY) -> Result<String, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    check_body_headers(parts)?;
+    let the_body = timeout(body_read_timeout(parts), Limited::new(body, MAX_BODY_SIZE as usize).collect()).await?
+        .map_err(|e| match e.downcast::<LengthLimitError>() {
+            Ok(_) => MyError::PayloadTooLarge { limit: MAX_BODY_SIZE },
+            Err(e) => MyError::BodyRead(e),
+        })?;
+    Ok(String::from_utf8(the_body.to_bytes().to_vec())?)
+}
+
+fn body_read_timeout(parts: &Parts) -> Duration {
+    parts.extensions.get::<BodyReadTimeout>().map_or(BODY_READ_TIMEOUT, |timeout| timeout.0)
+}
+
+/// Rejects requests whose body we would refuse anyway, based only on their headers.
+/// Bodies without a `Content-Length` (chunked) are still limited while they are being read.
+fn check_body_headers(parts: &Parts) -> Result<(), MyError> {
+    let content_length = parts.headers.get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > MAX_BODY_SIZE) {
+        return Err(MyError::PayloadTooLarge { limit: MAX_BODY_SIZE });
+    }
+    if let Some(content_type) = parts.headers.get(CONTENT_TYPE) {
+        let content_type = String::from_utf8_lossy(content_type.as_bytes());
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if !ACCEPTED_CONTENT_TYPES.contains(&media_type.as_str()) {
+            return Err(MyError::UnsupportedMediaType(media_type));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
-    use crate::good_service::GoodTowerService;
+    use crate::config::SharedConfig;
+    use crate::cors::{CorsConfig, CorsPolicy};
+    use crate::good_service::{GoodTowerService, MAX_BODY_SIZE};
+    use crate::io_stats::ServerStats;
+    use crate::server::serve_connection;
+    use bytes::Bytes;
+    use http_body_util::Empty;
+    use hyper::body::{Body, Frame};
+    use hyper::header::HeaderName;
     use hyper::service::Service;
+    use hyper::Method;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A body that fails the test if anyone tries to read it
+    struct UntouchableBody;
+
+    impl Body for UntouchableBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            panic!("The body of a rejected request must not be read");
+        }
+    }
 
     #[tokio::test]
     async fn test_endpoint() {
-        let service = GoodTowerService {};
+        let service = GoodTowerService::new();
 
         let body = http_body_util::Full::from("simple request");
         let req = hyper::Request::builder()
@@ -63,4 +343,266 @@ mod test {
         assert_eq!(resp.status(), 200);
         assert_eq!(resp.body(), "simple request");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let service = GoodTowerService::new();
+
+        let req = hyper::Request::builder()
+            .method("DELETE")
+            .uri("http://any-url:12345")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 405);
+        assert_eq!(resp.body(), r#"{"error":"method_not_allowed","message":"method DELETE is not allowed"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_body() {
+        let service = GoodTowerService::new();
+
+        let body = http_body_util::Full::from(vec![0xff, 0xfe]);
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345")
+            .body(body)
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 400);
+        assert!(resp.body().contains(r#""error":"decode""#));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_large_content_length_without_reading_body() {
+        let service = GoodTowerService::new();
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345")
+            .header("content-length", MAX_BODY_SIZE + 1)
+            .header("expect", "100-continue")
+            .body(UntouchableBody)
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 413);
+        assert!(resp.body().contains(r#""error":"payload_too_large""#));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unsupported_content_type_without_reading_body() {
+        let service = GoodTowerService::new();
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345")
+            .header("content-type", "image/png")
+            .body(UntouchableBody)
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 415);
+        assert!(resp.body().contains(r#""error":"unsupported_media_type""#));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_large_body_without_content_length() {
+        let service = GoodTowerService::new();
+
+        let body = http_body_util::Full::from(vec![b'a'; MAX_BODY_SIZE as usize + 1]);
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345")
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(body)
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 413);
+    }
+
+    /// Starts a server for a single connection and returns its address
+    async fn serve_once() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            serve_connection(stream, peer.into(), GoodTowerService::new(), Arc::new(ServerStats::default()), SharedConfig::default().current()).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_over_the_wire() {
+        // A rejected request gets its final response straight away, without a 100 Continue
+        let mut stream = TcpStream::connect(serve_once().await).await.unwrap();
+        stream.write_all(format!(
+            "POST / HTTP/1.1\r\nHost: test\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        ).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{response}");
+
+        // An accepted request is only asked for its body once it has passed the checks
+        let mut stream = BufReader::new(TcpStream::connect(serve_once().await).await.unwrap());
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: test\r\nExpect: 100-continue\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\n").await.unwrap();
+        let mut status_line = String::new();
+        stream.read_line(&mut status_line).await.unwrap();
+        assert_eq!(status_line, "HTTP/1.1 100 Continue\r\n");
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("hello"));
+    }
+
+    async fn kv_request(service: &GoodTowerService, method: &str, headers: &[(&str, &str)], body: &'static str) -> hyper::Response<String> {
+        let mut builder = hyper::Request::builder()
+            .method(method)
+            .uri("http://any-url:12345/kv/colour");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        service.call(builder.body(http_body_util::Full::from(body)).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_kv_lifecycle() {
+        let service = GoodTowerService::new();
+
+        assert_eq!(kv_request(&service, "GET", &[], "").await.status(), 404);
+
+        let created = kv_request(&service, "PUT", &[], "blue").await;
+        assert_eq!(created.status(), 201);
+        let etag = created.headers()["etag"].to_str().unwrap().to_string();
+
+        // Clones share the store, just like the connections of a server do
+        let resp = kv_request(&service.clone(), "GET", &[], "").await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["etag"], etag.as_str());
+        assert_eq!(resp.body(), "blue");
+
+        let replaced = kv_request(&service, "PUT", &[], "green").await;
+        assert_eq!(replaced.status(), 204);
+        assert_ne!(replaced.headers()["etag"], etag.as_str());
+
+        assert_eq!(kv_request(&service, "DELETE", &[], "").await.status(), 204);
+        assert_eq!(kv_request(&service, "DELETE", &[], "").await.status(), 404);
+        assert_eq!(kv_request(&service, "POST", &[], "").await.status(), 405);
+    }
+
+    #[tokio::test]
+    async fn test_kv_conditional_requests() {
+        let service = GoodTowerService::new();
+
+        let created = kv_request(&service, "PUT", &[("if-none-match", "*")], "blue").await;
+        assert_eq!(created.status(), 201);
+        let etag = created.headers()["etag"].to_str().unwrap().to_string();
+        // Creating again must fail, and must not need the body to find out
+        assert_eq!(kv_request(&service, "PUT", &[("if-none-match", "*")], "red").await.status(), 412);
+
+        let not_modified = kv_request(&service, "GET", &[("if-none-match", &etag)], "").await;
+        assert_eq!(not_modified.status(), 304);
+        assert_eq!(not_modified.body(), "");
+        assert_eq!(kv_request(&service, "GET", &[("if-none-match", r#""stale""#)], "").await.status(), 200);
+
+        assert_eq!(kv_request(&service, "PUT", &[("if-match", r#""stale""#)], "red").await.status(), 412);
+        assert_eq!(kv_request(&service, "PUT", &[("if-match", &etag)], "red").await.status(), 204);
+        assert_eq!(kv_request(&service, "DELETE", &[("if-match", &etag)], "").await.status(), 412);
+        assert_eq!(kv_request(&service, "GET", &[], "").await.body(), "red");
+    }
+
+    #[tokio::test]
+    async fn test_head_and_options() {
+        let service = GoodTowerService::new();
+        kv_request(&service, "PUT", &[], "blue").await;
+
+        let head = kv_request(&service, "HEAD", &[], "").await;
+        assert_eq!(head.status(), 200);
+        assert_eq!(head.headers()["content-length"], "4");
+        assert!(head.headers().contains_key("etag"));
+        assert_eq!(head.body(), "");
+
+        let options = kv_request(&service, "OPTIONS", &[], "").await;
+        assert_eq!(options.status(), 204);
+        assert_eq!(options.headers()["allow"], "GET, PUT, DELETE, HEAD, OPTIONS");
+
+        let not_allowed = kv_request(&service, "PATCH", &[], "").await;
+        assert_eq!(not_allowed.status(), 405);
+        assert_eq!(not_allowed.headers()["allow"], "GET, PUT, DELETE, HEAD, OPTIONS");
+
+        let req = hyper::Request::builder()
+            .method("OPTIONS")
+            .uri("http://any-url:12345/")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        assert_eq!(service.call(req).await.unwrap().headers()["allow"], "GET, POST, HEAD, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_per_origin() {
+        let cors = CorsConfig::default()
+            .allow_origin("https://app.example", CorsPolicy {
+                allowed_methods: Some(vec![Method::GET, Method::PUT]),
+                allowed_headers: vec![HeaderName::from_static("if-match")],
+                max_age: Some(Duration::from_secs(600)),
+                allow_credentials: false,
+            });
+        let service = GoodTowerService::new().with_cors(cors);
+        async fn preflight(service: &GoodTowerService, origin: &str, method: &str, headers: &str) -> hyper::Response<String> {
+            kv_request(service, "OPTIONS", &[
+                ("origin", origin),
+                ("access-control-request-method", method),
+                ("access-control-request-headers", headers),
+            ], "").await
+        }
+
+        let allowed = preflight(&service, "https://app.example", "PUT", "If-Match").await;
+        assert_eq!(allowed.status(), 204);
+        assert_eq!(allowed.headers()["access-control-allow-origin"], "https://app.example");
+        assert_eq!(allowed.headers()["access-control-allow-methods"], "GET, PUT");
+        assert_eq!(allowed.headers()["access-control-allow-headers"], "if-match");
+        assert_eq!(allowed.headers()["access-control-max-age"], "600");
+
+        // Unknown origins, methods outside the policy and unlisted headers get no CORS headers
+        for denied in [
+            preflight(&service, "https://evil.example", "PUT", "").await,
+            preflight(&service, "https://app.example", "DELETE", "").await,
+            preflight(&service, "https://app.example", "PUT", "x-secret").await,
+        ] {
+            assert_eq!(denied.status(), 204);
+            assert!(!denied.headers().contains_key("access-control-allow-origin"));
+        }
+
+        let actual = kv_request(&service, "GET", &[("origin", "https://app.example")], "").await;
+        assert_eq!(actual.headers()["access-control-allow-origin"], "https://app.example");
+        assert_eq!(actual.headers()["vary"], "origin");
+    }
+
+    #[tokio::test]
+    async fn test_head_over_the_wire() {
+        let mut stream = TcpStream::connect(serve_once().await).await.unwrap();
+        stream.write_all(b"HEAD / HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("content-length: 4\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_multipart_post() {
+        let service = GoodTowerService::new();
+
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"f.txt\"\r\n\r\nabc\r\n--b--\r\n";
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345")
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body(http_body_util::Full::from(body))
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.body(), r#"{"fields":[],"files":[{"field":"file","filename":"f.txt","content_type":null,"size":3}]}"#);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::listener::PeerAddr;
+use std::fmt::{Display, Formatter};
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Totals across every connection the server has handled
+#[derive(Default, Debug)]
+pub struct ServerStats {
+    pub connections_opened: AtomicU64,
+    pub connections_closed: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub requests: AtomicU64,
+}
+
+impl ServerStats {
+    /// Connections that have been opened but have not closed yet
+    pub fn active_connections(&self) -> u64 {
+        self.connections_opened.load(Ordering::Relaxed)
+            .saturating_sub(self.connections_closed.load(Ordering::Relaxed))
+    }
+}
+
+impl Display for ServerStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "active={} closed={} read={}B written={}B requests={}",
+            self.active_connections(),
+            self.connections_closed.load(Ordering::Relaxed),
+            self.bytes_read.load(Ordering::Relaxed),
+            self.bytes_written.load(Ordering::Relaxed),
+            self.requests.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters for a single connection.
+/// These are shared between the IO wrapper and the service, so that the service can count requests
+#[derive(Default, Debug)]
+pub struct ConnectionStats {
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub requests: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The summary emitted when a connection closes
+#[derive(Debug)]
+pub struct ConnectionSummary {
+    pub peer: PeerAddr,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub requests: u64,
+    pub duration: Duration,
+}
+
+impl Display for ConnectionSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "peer={} read={}B written={}B requests={} duration={:?}",
+            self.peer, self.bytes_read, self.bytes_written, self.requests, self.duration,
+        )
+    }
+}
+
+/// An IO wrapper that sits between the socket and `TokioIo`, counting traffic as it passes through.
+/// The counting only looks at how many bytes each call moved, so no data is copied.
+/// When the connection is dropped, a summary is emitted and the counts are added to the server totals.
+pub struct CountingIo<T> {
+    inner: T,
+    peer: PeerAddr,
+    opened_at: Instant,
+    stats: Arc<ConnectionStats>,
+    server_stats: Arc<ServerStats>,
+}
+
+impl<T> CountingIo<T> {
+    pub fn new(inner: T, peer: PeerAddr, server_stats: Arc<ServerStats>) -> Self {
+        server_stats.connections_opened.fetch_add(1, Ordering::Relaxed);
+        CountingIo {
+            inner,
+            peer,
+            opened_at: Instant::now(),
+            stats: Arc::new(ConnectionStats::default()),
+            server_stats,
+        }
+    }
+
+    /// The counters for this connection, for example to count requests from inside the service
+    pub fn stats(&self) -> Arc<ConnectionStats> {
+        self.stats.clone()
+    }
+
+    pub fn summary(&self) -> ConnectionSummary {
+        ConnectionSummary {
+            peer: self.peer.clone(),
+            bytes_read: self.stats.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.stats.bytes_written.load(Ordering::Relaxed),
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            duration: self.opened_at.elapsed(),
+        }
+    }
+}
+
+impl<T> Drop for CountingIo<T> {
+    fn drop(&mut self) {
+        let summary = self.summary();
+        self.server_stats.bytes_read.fetch_add(summary.bytes_read, Ordering::Relaxed);
+        self.server_stats.bytes_written.fetch_add(summary.bytes_written, Ordering::Relaxed);
+        self.server_stats.requests.fetch_add(summary.requests, Ordering::Relaxed);
+        self.server_stats.connections_closed.fetch_add(1, Ordering::Relaxed);
+        tracing::info!("Connection closed: {summary}; server totals: {}", self.server_stats);
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = (buf.filled().len() - before) as u64;
+            self.stats.bytes_read.fetch_add(read, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.stats.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = result {
+            self.stats.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io_stats::{CountingIo, ServerStats};
+    use crate::listener::PeerAddr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counts_traffic_and_folds_into_server_stats() {
+        let server_stats = Arc::new(ServerStats::default());
+        let (client, server) = duplex(64);
+        let mut io = CountingIo::new(server, PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap()), server_stats.clone());
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+
+        client_write.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        io.read_exact(&mut buffer).await.unwrap();
+        io.write_all(b"hi").await.unwrap();
+        io.stats().record_request();
+        client_read.read_exact(&mut buffer[..2]).await.unwrap();
+
+        let summary = io.summary();
+        assert_eq!(summary.bytes_read, 5);
+        assert_eq!(summary.bytes_written, 2);
+        assert_eq!(summary.requests, 1);
+        assert_eq!(server_stats.active_connections(), 1);
+
+        drop(io);
+        assert_eq!(server_stats.active_connections(), 0);
+        assert_eq!(server_stats.bytes_read.load(Ordering::Relaxed), 5);
+        assert_eq!(server_stats.bytes_written.load(Ordering::Relaxed), 2);
+        assert_eq!(server_stats.requests.load(Ordering::Relaxed), 1);
+    }
+}
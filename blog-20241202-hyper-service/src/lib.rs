@@ -0,0 +1,16 @@
+pub mod admin;
+pub mod bad_service;
+pub mod comparison;
+pub mod config;
+pub mod cors;
+pub mod error;
+pub mod good_service;
+pub mod io_stats;
+pub mod listener;
+pub mod multipart;
+pub mod rate_limit;
+pub mod recording;
+pub mod request_id;
+pub mod server;
+pub mod storage;
+pub mod supervisor;
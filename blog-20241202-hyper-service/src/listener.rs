@@ -0,0 +1,127 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Where a connection came from, or where a listener is bound
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix sockets on the client side are usually unnamed, hence the Option
+    Unix(Option<PathBuf>),
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+impl From<tokio::net::unix::SocketAddr> for PeerAddr {
+    fn from(addr: tokio::net::unix::SocketAddr) -> Self {
+        PeerAddr::Unix(addr.as_pathname().map(Path::to_path_buf))
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+/// Anything the server can accept connections from
+pub trait Listener: Send + 'static {
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output=std::io::Result<(Self::Io, PeerAddr)>> + Send;
+    fn local_addr(&self) -> std::io::Result<PeerAddr>;
+}
+
+impl Listener for TcpListener {
+    type Io = TcpStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Io, PeerAddr)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream, addr.into()))
+    }
+
+    fn local_addr(&self) -> std::io::Result<PeerAddr> {
+        TcpListener::local_addr(self).map(PeerAddr::from)
+    }
+}
+
+impl Listener for UnixListener {
+    type Io = UnixStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Io, PeerAddr)> {
+        let (stream, addr) = UnixListener::accept(self).await?;
+        Ok((stream, addr.into()))
+    }
+
+    fn local_addr(&self) -> std::io::Result<PeerAddr> {
+        UnixListener::local_addr(self).map(PeerAddr::from)
+    }
+}
+
+/// An address from configuration, such as `127.0.0.1:8080`, `[::1]:8080` or `unix:/run/app.sock`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => addr.parse().map(ListenAddr::Tcp),
+        }
+    }
+}
+
+/// Binds a unix socket, replacing a socket file left behind by a previous run.
+/// Anything at the path that is not a socket is left alone, and binding fails.
+pub fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::listener::{bind_unix, ListenAddr, Listener, PeerAddr};
+    use std::path::PathBuf;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!("127.0.0.1:80".parse(), Ok(ListenAddr::Tcp("127.0.0.1:80".parse().unwrap())));
+        assert_eq!("[::1]:80".parse(), Ok(ListenAddr::Tcp("[::1]:80".parse().unwrap())));
+        assert_eq!("unix:/tmp/a.sock".parse(), Ok(ListenAddr::Unix(PathBuf::from("/tmp/a.sock"))));
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unix_listener_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("hyper-service-listener-{}.sock", std::process::id()));
+        drop(bind_unix(&path).unwrap());
+        // The socket file is still there, which would normally make binding fail
+        let listener = bind_unix(&path).unwrap();
+        assert_eq!(Listener::local_addr(&listener).unwrap(), PeerAddr::Unix(Some(path.clone())));
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (_stream, peer) = Listener::accept(&listener).await.unwrap();
+        assert_eq!(peer.to_string(), "unix:(unnamed)");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,22 +1,92 @@
-use crate::bad_service::BadTowerService;
-use crate::good_service::GoodTowerService;
+use blog_20241202_hyper_service::admin::AdminService;
+use blog_20241202_hyper_service::bad_service::BadTowerService;
+use blog_20241202_hyper_service::comparison::{compare, Report};
+use blog_20241202_hyper_service::config;
+use blog_20241202_hyper_service::config::{ServerConfig, SharedConfig};
+use blog_20241202_hyper_service::good_service::GoodTowerService;
+use blog_20241202_hyper_service::listener::{bind_unix, ListenAddr, PeerAddr};
+use blog_20241202_hyper_service::recording::{load_recording, replay, Recorder, RecordingService};
+use blog_20241202_hyper_service::request_id::RequestIdLayer;
+use blog_20241202_hyper_service::server::{bind_reuseport, AcceptorRuntime, Server};
+use blog_20241202_hyper_service::supervisor::ShutdownMode;
+use hyper::body::Incoming;
+use hyper::service::Service;
+use hyper::{Request, Response};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tower::Layer;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload};
 
-mod bad_service;
-mod error;
-mod good_service;
+/// How long connections get to finish after ctrl-c before they are aborted
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// Where we listen when LISTEN is not set
+const DEFAULT_LISTEN: &str = "127.0.0.1:0";
+/// How often the CONFIG file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[tokio::main]
 async fn main() {
+    // `replay <recording>` feeds a recording made with RECORD_TO back through the service, and
+    // `compare` reports how the bad and the good service differ
+    let args: Vec<String> = std::env::args().collect();
+    match args.as_slice() {
+        [_, command, recording] if command == "replay" => {
+            replay_recording(recording).await;
+            return;
+        }
+        [_, command] if command == "compare" => {
+            compare_services().await;
+            return;
+        }
+        _ => {}
+    }
     println!("Hello, world!");
-    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
-    let listener = TcpListener::from_std(listener).unwrap();
-    let bind_addr = listener.local_addr().unwrap();
-    println!("Listening on http://{}", bind_addr);
-    #[cfg(feature = "bad-impl")]
-    bad_solution(listener).await;
-    #[cfg(not(feature = "bad-impl"))]
-    good_solution(listener).await;
+    if std::env::var("SERVICE").as_deref() == Ok("bad") {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        println!("Listening on http://{}", listener.local_addr().unwrap());
+        return bad_solution(listener).await;
+    }
+    match std::env::var("RECORD_TO") {
+        Ok(path) => {
+            println!("Recording traffic to {path}");
+            let recorder = Recorder::open(path).unwrap();
+            let service = RecordingService::new(GoodTowerService::new(), recorder);
+            good_solution(RequestIdLayer::new().layer(service)).await
+        }
+        Err(_) => good_solution(RequestIdLayer::new().layer(GoodTowerService::new())).await,
+    }
+}
+
+async fn replay_recording(path: &str) {
+    let file = std::fs::File::open(path).unwrap();
+    let recording = load_recording(std::io::BufReader::new(file)).unwrap();
+    let differences = replay(&recording, &GoodTowerService::new()).await;
+    for difference in &differences {
+        println!("{difference}");
+    }
+    println!("Replayed {} exchanges, {} differences", recording.len(), differences.len());
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Serves both services on the same kind of server, and prints every scenario they disagree on
+async fn compare_services() {
+    let mut server = Server::start();
+    let bad = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), Arc::new(BadTowerService {})).unwrap();
+    let good = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), GoodTowerService::new()).unwrap();
+    let (PeerAddr::Tcp(bad), PeerAddr::Tcp(good)) = (bad, good) else {
+        unreachable!("TCP listeners have TCP addresses");
+    };
+    print!("{}", Report(compare(bad, good).await));
+    server.shutdown(ShutdownMode::Abort).await;
 }
 
 async fn bad_solution(listener: TcpListener) {
@@ -37,20 +107,87 @@ async fn bad_solution(listener: TcpListener) {
     }
 }
 
-async fn good_solution(listener: TcpListener) {
-    loop {
-        let (tcp_stream, addr) = listener.accept().await.unwrap();
-        println!("Received connection from {addr:?}, spawning");
-        tokio::spawn(async move {
-            let tcp_stream = hyper_util::rt::TokioIo::new(tcp_stream);
-            let result = hyper::server::conn::http1::Builder::new()
-                .keep_alive(false)
-                .serve_connection(tcp_stream, GoodTowerService {}).await;
-            if let Err(e) = result {
-                eprintln!("Error: {:?}", e);
-            }
-            println!("Finished serving connection for {addr:?}");
-        });
-        // tokio::task::yield_now().await;
+/// Serves on every address in LISTEN (comma separated, `unix:/path` for unix sockets), and serves
+/// the admin routes on ADMIN_LISTEN if it is set. Settings come from the CONFIG file, see [load_config].
+/// Alternatively, ACCEPTORS listeners share one TCP port via SO_REUSEPORT, and
+/// ACCEPTOR_RUNTIME=per-acceptor gives every accept loop its own current-thread runtime.
+async fn good_solution<S>(service: S)
+where
+    S: Service<Request<Incoming>, Response=Response<String>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let config = load_config();
+    init_logging(&config);
+    let mut server = Server::start_with_config(config);
+    let acceptors: usize = std::env::var("ACCEPTORS").ok()
+        .map(|count| count.parse().expect("ACCEPTORS must be a number"))
+        .unwrap_or(1);
+    if acceptors <= 1 {
+        let addrs = std::env::var("LISTEN").unwrap_or_else(|_| DEFAULT_LISTEN.to_string());
+        for addr in addrs.split(',') {
+            let addr = serve_on(&mut server, addr.trim(), service.clone()).await;
+            println!("Listening on {addr}");
+        }
+    } else {
+        let runtime = match std::env::var("ACCEPTOR_RUNTIME").as_deref() {
+            Ok("per-acceptor") => AcceptorRuntime::PerAcceptor,
+            _ => AcceptorRuntime::Shared,
+        };
+        let listeners = bind_reuseport(DEFAULT_LISTEN.parse().unwrap(), acceptors).unwrap();
+        println!("Listening on http://{} with {acceptors} {runtime:?} acceptors", listeners[0].local_addr().unwrap());
+        server.serve_acceptors(listeners, runtime, service).unwrap();
+    }
+    if let Ok(addr) = std::env::var("ADMIN_LISTEN") {
+        let admin = AdminService::new(server.stats(), server.supervisor().clone());
+        let addr = serve_on(&mut server, &addr, admin).await;
+        println!("Admin routes on {addr}");
     }
-}
\ No newline at end of file
+
+    tokio::signal::ctrl_c().await.unwrap();
+    println!("Shutting down, waiting for {} connections", server.supervisor().live_connections());
+    let report = server.shutdown(ShutdownMode::Graceful(SHUTDOWN_GRACE_PERIOD)).await;
+    println!("Connections: {} completed, {} panicked, {} aborted", report.completed, report.panicked, report.aborted);
+}
+
+/// The config from the JSON file named by CONFIG, reloaded on SIGHUP or when the file changes.
+/// Without CONFIG, the defaults are used for the lifetime of the process.
+fn load_config() -> SharedConfig {
+    let Ok(path) = std::env::var("CONFIG") else {
+        return SharedConfig::default();
+    };
+    let path = PathBuf::from(path);
+    let config = ServerConfig::load(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    let shared = SharedConfig::new(config).unwrap();
+    tokio::spawn(config::watch(path, shared.clone(), CONFIG_POLL_INTERVAL));
+    shared
+}
+
+/// Logs to stdout at the level from the config, following it across reloads
+fn init_logging(config: &SharedConfig) {
+    let (level, level_handle) = reload::Layer::new(LevelFilter::from(config.current().config().log_level));
+    tracing_subscriber::registry().with(level).with(fmt::layer()).init();
+    let mut changes = config.subscribe();
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            let level = changes.borrow_and_update().config().log_level;
+            if let Err(e) = level_handle.modify(|filter| *filter = level.into()) {
+                eprintln!("Failed to change the log level: {e}");
+            }
+        }
+    });
+}
+
+/// Binds `addr` and serves `service` on it, returning the address actually bound
+async fn serve_on<S>(server: &mut Server, addr: &str, service: S) -> PeerAddr
+where
+    S: Service<Request<Incoming>, Response=Response<String>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let result = match addr.parse().expect("listen addresses look like 127.0.0.1:8080, [::1]:8080 or unix:/path") {
+        ListenAddr::Tcp(addr) => server.serve(TcpListener::bind(addr).await.unwrap(), service),
+        ListenAddr::Unix(path) => server.serve(bind_unix(&path).unwrap(), service),
+    };
+    result.unwrap()
+}
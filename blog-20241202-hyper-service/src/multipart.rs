@@ -0,0 +1,204 @@
+use crate::error::MyError;
+use bytes::Bytes;
+use http_body_util::BodyDataStream;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::request::Parts;
+use hyper::{Response, StatusCode};
+use multer::{Constraints, Multipart, SizeLimit};
+use serde::Serialize;
+
+pub const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+
+/// How much of a multipart body we are willing to receive
+#[derive(Clone, Debug)]
+pub struct MultipartLimits {
+    /// The largest single part, file or field
+    pub per_part: u64,
+    /// The largest body, all parts together
+    pub total: u64,
+    /// Text fields are kept in memory for the summary, so they get a smaller limit than files
+    pub text_field: u64,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            per_part: 8 * 1024 * 1024,
+            total: 32 * 1024 * 1024,
+            text_field: 64 * 1024,
+        }
+    }
+}
+
+/// What we received in a multipart body
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MultipartSummary {
+    pub fields: Vec<FieldSummary>,
+    pub files: Vec<FileSummary>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldSummary {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FileSummary {
+    pub field: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+}
+
+/// Whether the request carries a multipart/form-data body
+pub fn is_multipart(parts: &Parts) -> bool {
+    parts.headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(MULTIPART_FORM_DATA))
+}
+
+/// Reads a multipart/form-data body part by part and responds with a JSON summary of it.
+/// File contents are counted as they stream past and never held in memory.
+pub async fn summarize<BODY>(parts: &Parts, body: BODY, limits: &MultipartLimits) -> Result<Response<String>, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let summary = read_summary(parts, body, limits).await?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&summary).unwrap())
+        .unwrap())
+}
+
+async fn read_summary<BODY>(parts: &Parts, body: BODY, limits: &MultipartLimits) -> Result<MultipartSummary, MyError>
+where
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+    BODY::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // Like any other body, we reject what we can before reading it
+    let content_length = parts.headers.get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > limits.total) {
+        return Err(MyError::PayloadTooLarge { limit: limits.total });
+    }
+    let content_type = parts.headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    let boundary = multer::parse_boundary(content_type).map_err(MyError::Multipart)?;
+
+    let constraints = Constraints::new()
+        .size_limit(SizeLimit::new().per_field(limits.per_part).whole_stream(limits.total));
+    let mut multipart = Multipart::with_constraints(BodyDataStream::new(body), boundary, constraints);
+    let mut summary = MultipartSummary::default();
+    while let Some(mut field) = multipart.next_field().await.map_err(from_multer)? {
+        let name = field.name().unwrap_or_default().to_string();
+        match field.file_name().map(str::to_string) {
+            Some(filename) => {
+                let content_type = field.content_type().map(|mime| mime.to_string());
+                let mut size = 0;
+                while let Some(chunk) = field.chunk().await.map_err(from_multer)? {
+                    size += chunk.len() as u64;
+                }
+                summary.files.push(FileSummary { field: name, filename, content_type, size });
+            }
+            None => {
+                let mut value = Vec::new();
+                while let Some(chunk) = field.chunk().await.map_err(from_multer)? {
+                    if (value.len() + chunk.len()) as u64 > limits.text_field {
+                        return Err(MyError::PayloadTooLarge { limit: limits.text_field });
+                    }
+                    value.extend_from_slice(&chunk);
+                }
+                summary.fields.push(FieldSummary { name, value: String::from_utf8(value)? });
+            }
+        }
+    }
+    Ok(summary)
+}
+
+fn from_multer(e: multer::Error) -> MyError {
+    match e {
+        multer::Error::FieldSizeExceeded { limit, .. } | multer::Error::StreamSizeExceeded { limit } => {
+            MyError::PayloadTooLarge { limit }
+        }
+        multer::Error::StreamReadFailed(e) => MyError::BodyRead(e),
+        e => MyError::Multipart(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::MyError;
+    use crate::multipart::{read_summary, FieldSummary, FileSummary, MultipartLimits};
+    use http_body_util::Full;
+    use hyper::Request;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn body() -> String {
+        [
+            "--X-BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"title\"\r\n\r\n",
+            "Holiday photos\r\n",
+            "--X-BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "0123456789\r\n",
+            "--X-BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"upload\"; filename=\"b.bin\"\r\n\r\n",
+            "\r\n",
+            "--X-BOUNDARY--\r\n",
+        ].concat()
+    }
+
+    async fn summarize(content_type: &str, body: String, limits: &MultipartLimits) -> Result<crate::multipart::MultipartSummary, MyError> {
+        let (parts, body) = Request::builder()
+            .method("POST")
+            .header("content-type", content_type)
+            .body(Full::from(body))
+            .unwrap()
+            .into_parts();
+        read_summary(&parts, body, limits).await
+    }
+
+    #[tokio::test]
+    async fn test_summarizes_fields_and_files() {
+        let summary = summarize(&format!("multipart/form-data; boundary={BOUNDARY}"), body(), &MultipartLimits::default()).await.unwrap();
+        assert_eq!(summary.fields, vec![FieldSummary { name: "title".to_string(), value: "Holiday photos".to_string() }]);
+        assert_eq!(summary.files, vec![
+            FileSummary { field: "upload".to_string(), filename: "a.txt".to_string(), content_type: Some("text/plain".to_string()), size: 10 },
+            FileSummary { field: "upload".to_string(), filename: "b.bin".to_string(), content_type: None, size: 0 },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_enforces_limits() {
+        let content_type = format!("multipart/form-data; boundary={BOUNDARY}");
+        let per_part = MultipartLimits { per_part: 5, ..Default::default() };
+        assert!(matches!(summarize(&content_type, body(), &per_part).await, Err(MyError::PayloadTooLarge { limit: 5 })));
+        let total = MultipartLimits { total: 100, ..Default::default() };
+        assert!(matches!(summarize(&content_type, body(), &total).await, Err(MyError::PayloadTooLarge { limit: 100 })));
+        let text_field = MultipartLimits { text_field: 4, ..Default::default() };
+        assert!(matches!(summarize(&content_type, body(), &text_field).await, Err(MyError::PayloadTooLarge { limit: 4 })));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_malformed_boundaries() {
+        let limits = MultipartLimits::default();
+        // No boundary parameter at all
+        let result = summarize("multipart/form-data", body(), &limits).await;
+        assert!(matches!(result, Err(MyError::Multipart(multer::Error::NoBoundary))), "{result:?}");
+        // A boundary that never appears in the body
+        let result = summarize("multipart/form-data; boundary=OTHER", body(), &limits).await;
+        assert!(matches!(result, Err(MyError::Multipart(_))), "{result:?}");
+        // A body that stops before the closing boundary
+        let truncated = body().replace("--X-BOUNDARY--\r\n", "");
+        let result = summarize(&format!("multipart/form-data; boundary={BOUNDARY}"), truncated, &limits).await;
+        assert!(matches!(result, Err(MyError::Multipart(_))), "{result:?}");
+    }
+}
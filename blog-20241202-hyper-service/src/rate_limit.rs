@@ -0,0 +1,75 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_second` requests per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        RateLimiter {
+            per_second,
+            burst: burst as f64,
+            bucket: Mutex::new(Bucket { tokens: burst as f64, refilled_at: Instant::now() }),
+        }
+    }
+
+    /// Takes a token if there is one. Requests that get none should be turned away.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket();
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token is available, useful for a `Retry-After` header
+    pub fn retry_after(&self) -> Duration {
+        let bucket = self.bucket();
+        Duration::from_secs_f64(((1.0 - bucket.tokens) / self.per_second).max(0.0))
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        // The bucket is always left consistent, so a panic elsewhere while holding it is harmless
+        self.bucket.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rate_limit::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_bursts_then_refills() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+        assert!((0..3).all(|_| limiter.try_acquire_at(start)));
+        assert!(!limiter.try_acquire_at(start));
+        // Half a second buys one token at two per second
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(500)));
+        // A long pause refills the bucket, but never beyond the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| limiter.try_acquire_at(later)).count(), 3);
+    }
+}
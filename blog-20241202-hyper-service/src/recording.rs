@@ -0,0 +1,429 @@
+use crate::error::MyError;
+use crate::request_id::{RequestId, REQUEST_ID_HEADER};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::body::{Frame, SizeHint};
+use hyper::{HeaderMap, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+/// A single request and the response the service gave to it, as stored in a recording
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedExchange {
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    /// Request bodies are not necessarily UTF-8, so we keep the raw bytes
+    pub request_body: Vec<u8>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+    pub duration_micros: u64,
+}
+
+impl RecordedExchange {
+    /// Rebuilds the recorded request so it can be sent to a service again.
+    /// A recorded request id is restored as an extension too, so the service sees the request
+    /// just as it did when it was recorded.
+    pub fn to_request(&self) -> Result<Request<Full<Bytes>>, hyper::http::Error> {
+        let mut builder = Request::builder()
+            .method(self.method.as_str())
+            .uri(self.uri.as_str());
+        for (name, value) in &self.request_headers {
+            builder = builder.header(name, value);
+        }
+        let mut request = builder.body(Full::from(self.request_body.clone()))?;
+        if let Some(request_id) = request.headers().get(&REQUEST_ID_HEADER).and_then(RequestId::from_header) {
+            request.extensions_mut().insert(request_id);
+        }
+        Ok(request)
+    }
+}
+
+/// How many exchanges may wait to be written before recording slows the service down
+const RECORDING_QUEUE_SIZE: usize = 1024;
+
+enum RecorderCommand {
+    Line(Vec<u8>),
+    Flush(oneshot::Sender<std::io::Result<()>>),
+}
+
+/// Writes recorded exchanges as JSON lines.
+/// Writing is blocking, so it happens on a dedicated blocking task, one line at a time, which
+/// also means concurrent connections don't interleave lines
+pub struct Recorder<W> {
+    sender: mpsc::Sender<RecorderCommand>,
+    writer: Arc<Mutex<W>>,
+}
+
+// Derive would require W: Clone, but the writer itself is shared and never cloned
+impl<W> Clone for Recorder<W> {
+    fn clone(&self) -> Self {
+        Recorder {
+            sender: self.sender.clone(),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl Recorder<File> {
+    /// Appends to the file at `path`, creating it if necessary
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder::new(file))
+    }
+}
+
+impl<W: Write + Send + 'static> Recorder<W> {
+    /// Starts the writer task, so this must be called from within a tokio runtime.
+    /// The task stops once every clone of the recorder is gone.
+    pub fn new(writer: W) -> Self {
+        let writer = Arc::new(Mutex::new(writer));
+        let (sender, mut receiver) = mpsc::channel(RECORDING_QUEUE_SIZE);
+        let task_writer = writer.clone();
+        tokio::task::spawn_blocking(move || {
+            while let Some(command) = receiver.blocking_recv() {
+                let mut writer = lock(&task_writer);
+                match command {
+                    RecorderCommand::Line(line) => {
+                        // A broken recording should not take the service down with it
+                        if let Err(e) = writer.write_all(&line).and_then(|_| writer.flush()) {
+                            tracing::error!("Failed to record exchange: {e}");
+                        }
+                    }
+                    RecorderCommand::Flush(done) => {
+                        let _ = done.send(writer.flush());
+                    }
+                }
+            }
+        });
+        Recorder { sender, writer }
+    }
+}
+
+impl<W> Recorder<W> {
+    /// Queues the exchange to be written. This only waits if the writer has fallen far behind.
+    pub async fn record(&self, exchange: &RecordedExchange) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(exchange)?;
+        line.push(b'\n');
+        self.sender.send(RecorderCommand::Line(line)).await.map_err(|_| writer_stopped())
+    }
+
+    /// Waits until everything recorded so far has been written
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let (done, written) = oneshot::channel();
+        self.sender.send(RecorderCommand::Flush(done)).await.map_err(|_| writer_stopped())?;
+        written.await.map_err(|_| writer_stopped())?
+    }
+
+    /// The writer, as it is after the exchanges written so far. See [Recorder::flush].
+    pub fn writer(&self) -> MutexGuard<'_, W> {
+        lock(&self.writer)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic while holding the lock can at worst leave a partial line, which we can live with
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn writer_stopped() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the recording writer has stopped")
+}
+
+/// Reads a recording produced by a [Recorder]
+pub fn load_recording(reader: impl BufRead) -> std::io::Result<Vec<RecordedExchange>> {
+    reader.lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Passes a request body through untouched, keeping a copy of every data frame that is read.
+/// How much of the body is read, and how quickly, is still up to the service reading it, so its
+/// limits apply as they would without recording.
+pub struct RecordedBody<BODY> {
+    inner: Pin<Box<BODY>>,
+    copy: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<BODY> hyper::body::Body for RecordedBody<BODY>
+where
+    BODY: hyper::body::Body<Data=Bytes>,
+{
+    type Data = Bytes;
+    type Error = BODY::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let frame = ready!(self.inner.as_mut().poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|frame| frame.as_ref().ok()).and_then(Frame::data_ref) {
+            lock(&self.copy).extend_from_slice(data);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Captures every request and response served by the inner service.
+/// The inner service reads the request body through a [RecordedBody], so only what it chose to
+/// read is recorded; a body it rejected up front is recorded as empty.
+pub struct RecordingService<S, W> {
+    inner: S,
+    recorder: Recorder<W>,
+}
+
+impl<S: Clone, W> Clone for RecordingService<S, W> {
+    fn clone(&self) -> Self {
+        RecordingService {
+            inner: self.inner.clone(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+impl<S, W> RecordingService<S, W> {
+    pub fn new(inner: S, recorder: Recorder<W>) -> Self {
+        RecordingService { inner, recorder }
+    }
+}
+
+impl<S, W, BODY> hyper::service::Service<Request<BODY>> for RecordingService<S, W>
+where
+    S: hyper::service::Service<Request<RecordedBody<BODY>>, Response=Response<String>, Error=MyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    W: Send + 'static,
+    BODY: hyper::body::Body<Data=Bytes> + Send + 'static,
+{
+    type Response = Response<String>;
+    type Error = MyError;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<BODY>) -> Self::Future {
+        let inner = self.inner.clone();
+        let recorder = self.recorder.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let method = parts.method.to_string();
+            let uri = parts.uri.to_string();
+            let mut request_headers = header_pairs(&parts.headers);
+            // A generated request id ends up in the response, so it is part of what we must replay
+            if let Some(request_id) = parts.extensions.get::<RequestId>() {
+                if !parts.headers.contains_key(&REQUEST_ID_HEADER) {
+                    request_headers.push((REQUEST_ID_HEADER.to_string(), request_id.to_string()));
+                }
+            }
+
+            let copy = Arc::new(Mutex::new(Vec::new()));
+            let body = RecordedBody { inner: Box::pin(body), copy: copy.clone() };
+            let started = Instant::now();
+            let response = inner.call(Request::from_parts(parts, body)).await?;
+            let exchange = RecordedExchange {
+                method,
+                uri,
+                request_headers,
+                request_body: std::mem::take(&mut *lock(&copy)),
+                status: response.status().as_u16(),
+                response_headers: header_pairs(response.headers()),
+                response_body: response.body().clone(),
+                duration_micros: started.elapsed().as_micros() as u64,
+            };
+            if let Err(e) = recorder.record(&exchange).await {
+                tracing::error!("Failed to record exchange: {e}");
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect()
+}
+
+/// A place where a replayed response did not match the recording
+#[derive(Debug, PartialEq)]
+pub struct ReplayDifference {
+    /// The position of the exchange in the recording, starting at 1
+    pub exchange: usize,
+    pub method: String,
+    pub uri: String,
+    pub field: &'static str,
+    pub recorded: String,
+    pub replayed: String,
+}
+
+impl Display for ReplayDifference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {}: {} differs, recorded {:?} but got {:?}",
+            self.exchange, self.method, self.uri, self.field, self.recorded, self.replayed,
+        )
+    }
+}
+
+/// Feeds every recorded request through `service` and reports where the responses differ
+pub async fn replay<S>(exchanges: &[RecordedExchange], service: &S) -> Vec<ReplayDifference>
+where
+    S: hyper::service::Service<Request<Full<Bytes>>, Response=Response<String>>,
+    S::Error: Display,
+{
+    let mut differences = Vec::new();
+    for (index, exchange) in exchanges.iter().enumerate() {
+        let mut difference = |field: &'static str, recorded: String, replayed: String| {
+            differences.push(ReplayDifference {
+                exchange: index + 1,
+                method: exchange.method.clone(),
+                uri: exchange.uri.clone(),
+                field,
+                recorded,
+                replayed,
+            });
+        };
+        let request = match exchange.to_request() {
+            Ok(request) => request,
+            Err(e) => {
+                difference("request", exchange.uri.clone(), format!("unreplayable request: {e}"));
+                continue;
+            }
+        };
+        let response = match service.call(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                difference("status", exchange.status.to_string(), format!("error: {e}"));
+                continue;
+            }
+        };
+        if response.status().as_u16() != exchange.status {
+            difference("status", exchange.status.to_string(), response.status().as_u16().to_string());
+        }
+        let recorded_headers = to_header_map(&exchange.response_headers);
+        if &recorded_headers != response.headers() {
+            difference("headers", format!("{:?}", recorded_headers), format!("{:?}", response.headers()));
+        }
+        if response.body() != &exchange.response_body {
+            difference("body", exchange.response_body.clone(), response.body().clone());
+        }
+    }
+    differences
+}
+
+fn to_header_map(pairs: &[(String, String)]) -> HeaderMap {
+    pairs.iter()
+        .filter_map(|(name, value)| {
+            Some((HeaderName::try_from(name.as_str()).ok()?, HeaderValue::try_from(value.as_str()).ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::good_service::{GoodTowerService, MAX_BODY_SIZE};
+    use crate::recording::{load_recording, replay, Recorder, RecordingService};
+    use crate::request_id::RequestIdLayer;
+    use tower::Layer;
+    use hyper::service::{service_fn, Service};
+    use hyper::Response;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let recorder = Recorder::new(Vec::new());
+        let service = RecordingService::new(GoodTowerService::new(), recorder.clone());
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345/echo")
+            .header("x-test", "yes")
+            .body(http_body_util::Full::from("simple request"))
+            .unwrap();
+        service.call(req).await.unwrap();
+        let req = hyper::Request::builder()
+            .method("DELETE")
+            .uri("http://any-url:12345/")
+            .body(http_body_util::Full::from(""))
+            .unwrap();
+        service.call(req).await.unwrap();
+
+        recorder.flush().await.unwrap();
+        let recording = load_recording(recorder.writer().as_slice()).unwrap();
+        assert_eq!(recording.len(), 2);
+        assert_eq!(recording[0].method, "POST");
+        assert_eq!(recording[0].request_headers, vec![("x-test".to_string(), "yes".to_string())]);
+        assert_eq!(recording[0].request_body, b"simple request");
+        assert_eq!(recording[0].response_body, "simple request");
+        assert_eq!(recording[1].status, 405);
+
+        // Requests that went through the request id layer are replayed with the same id
+        let tagged = RequestIdLayer::new().layer(service.clone());
+        let req = hyper::Request::builder()
+            .method("PATCH")
+            .uri("http://any-url:12345/")
+            .body(http_body_util::Full::from(""))
+            .unwrap();
+        let resp = Service::call(&tagged, req).await.unwrap();
+        let request_id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+        recorder.flush().await.unwrap();
+        let recording = load_recording(recorder.writer().as_slice()).unwrap();
+        assert_eq!(recording.len(), 3);
+        assert!(recording[2].request_headers.contains(&("x-request-id".to_string(), request_id.clone())));
+        assert!(recording[2].response_body.contains(&request_id));
+
+        // Replaying against the same service shows no differences
+        assert!(replay(&recording, &GoodTowerService::new()).await.is_empty());
+
+        // Replaying against a changed service shows what changed
+        let changed = service_fn(|_req| async { Ok::<_, std::convert::Infallible>(Response::new("changed".to_string())) });
+        let differences = replay(&recording, &changed).await;
+        let fields: Vec<_> = differences.iter().map(|d| (d.exchange, d.field)).collect();
+        assert_eq!(fields, vec![(1, "body"), (2, "status"), (2, "headers"), (2, "body"), (3, "status"), (3, "headers"), (3, "body")]);
+    }
+
+    #[tokio::test]
+    async fn test_recording_keeps_body_limits() {
+        let recorder = Recorder::new(Vec::new());
+        let service = RecordingService::new(GoodTowerService::new(), recorder.clone());
+
+        // The inner service refuses the body from its headers alone, so none of it is read or recorded
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345/echo")
+            .header("content-length", MAX_BODY_SIZE + 1)
+            .body(http_body_util::Full::from("not read"))
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 413);
+
+        // A body without a length is limited while it is read
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://any-url:12345/echo")
+            .body(http_body_util::Full::from(vec![b'a'; MAX_BODY_SIZE as usize + 1]))
+            .unwrap();
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), 413);
+
+        recorder.flush().await.unwrap();
+        let recording = load_recording(recorder.writer().as_slice()).unwrap();
+        assert_eq!(recording.len(), 2);
+        assert!(recording[0].request_body.is_empty());
+        assert_eq!(recording[1].status, 413);
+    }
+}
@@ -0,0 +1,182 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Request, Response};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::Layer;
+use tracing::{Instrument, Span};
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Incoming ids longer than this are replaced, so clients cannot bloat our logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of the request being handled, available from the request extensions
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    /// Reuses the id the client sent if it is reasonable, and generates one otherwise
+    fn from_request<B>(req: &Request<B>) -> Self {
+        req.headers().get(&REQUEST_ID_HEADER)
+            .and_then(Self::from_header)
+            .unwrap_or_else(Self::generate)
+    }
+
+    /// Accepts an id from a header value, unless it is empty, too long or not visible ASCII
+    pub fn from_header(id: &HeaderValue) -> Option<Self> {
+        Self::is_acceptable(id).then(|| RequestId(id.clone()))
+    }
+
+    fn is_acceptable(id: &HeaderValue) -> bool {
+        let id = id.as_bytes();
+        !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.iter().all(u8::is_ascii_graphic)
+    }
+
+    fn generate() -> Self {
+        // A UUID is always a valid header value
+        RequestId(HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).unwrap())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only visible ASCII gets in, so this cannot fail
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Gives every request an `X-Request-Id`, reusing the incoming one if present.
+/// The id is put in the request extensions, on a tracing span around the inner service, and on
+/// the response headers.
+#[derive(Clone, Default)]
+pub struct RequestIdLayer {}
+
+impl RequestIdLayer {
+    pub fn new() -> Self {
+        RequestIdLayer {}
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+/// The service created by [RequestIdLayer].
+/// It is a tower service when the inner service is a tower service, and a hyper service when the
+/// inner service is a hyper service, so it can sit in front of either.
+#[derive(Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+/// Tags the request, and returns the span that the inner service should run in
+fn prepare<B>(req: &mut Request<B>) -> (RequestId, Span) {
+    let request_id = RequestId::from_request(req);
+    req.extensions_mut().insert(request_id.clone());
+    let span = tracing::info_span!("request", request_id = %request_id, method = %req.method(), uri = %req.uri());
+    (request_id, span)
+}
+
+fn tag_response<ResBody>(request_id: RequestId, mut response: Response<ResBody>) -> Response<ResBody> {
+    response.headers_mut().insert(REQUEST_ID_HEADER.clone(), request_id.0);
+    response
+}
+
+impl<S, ReqBody, ResBody> tower::Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: tower::Service<Request<ReqBody>, Response=Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let (request_id, span) = prepare(&mut req);
+        let future = span.in_scope(|| self.inner.call(req));
+        Box::pin(async move {
+            future.await.map(|response| tag_response(request_id, response))
+        }.instrument(span))
+    }
+}
+
+impl<S, ReqBody, ResBody> hyper::service::Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: hyper::service::Service<Request<ReqBody>, Response=Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, mut req: Request<ReqBody>) -> Self::Future {
+        let (request_id, span) = prepare(&mut req);
+        let future = span.in_scope(|| self.inner.call(req));
+        Box::pin(async move {
+            future.await.map(|response| tag_response(request_id, response))
+        }.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::good_service::GoodTowerService;
+    use crate::request_id::{RequestId, RequestIdLayer};
+    use http_body_util::Full;
+    use hyper::{Request, Response};
+    use tower::{service_fn, Layer, Service, ServiceExt};
+
+    fn request(request_id: Option<&str>) -> Request<Full<bytes::Bytes>> {
+        let mut builder = Request::builder().method("DELETE").uri("http://any-url:12345/");
+        if let Some(request_id) = request_id {
+            builder = builder.header("x-request-id", request_id);
+        }
+        builder.body(Full::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tower_service_sees_and_echoes_the_id() {
+        // The inner service reports the id it found in the extensions
+        let inner = service_fn(|req: Request<Full<bytes::Bytes>>| async move {
+            let id = req.extensions().get::<RequestId>().unwrap().to_string();
+            Ok::<_, std::convert::Infallible>(Response::new(id))
+        });
+        let mut service = RequestIdLayer::new().layer(inner);
+
+        let resp = service.ready().await.unwrap().call(request(Some("abc-123"))).await.unwrap();
+        assert_eq!(resp.headers()["x-request-id"], "abc-123");
+        assert_eq!(resp.body(), "abc-123");
+
+        // Missing and unreasonable ids are replaced by generated ones
+        for request_id in [None, Some("has spaces"), Some(&*"x".repeat(200))] {
+            let resp = service.ready().await.unwrap().call(request(request_id)).await.unwrap();
+            let generated = resp.headers()["x-request-id"].to_str().unwrap();
+            assert_eq!(generated.len(), 36);
+            assert_eq!(resp.body(), generated);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hyper_service_puts_the_id_in_error_bodies() {
+        let service = RequestIdLayer::new().layer(GoodTowerService::new());
+
+        let resp = hyper::service::Service::call(&service, request(Some("abc-123"))).await.unwrap();
+        assert_eq!(resp.status(), 405);
+        assert_eq!(resp.headers()["x-request-id"], "abc-123");
+        assert!(resp.body().contains(r#""request_id":"abc-123""#), "{}", resp.body());
+    }
+}
@@ -0,0 +1,438 @@
+use crate::config::{ActiveConfig, BodyReadTimeout, SharedConfig};
+use crate::error::MyError;
+use crate::io_stats::{CountingIo, ServerStats};
+use crate::listener::{Listener, PeerAddr};
+use crate::rate_limit::RateLimiter;
+use crate::supervisor::{ConnectionSupervisor, ShutdownMode, SupervisorReport};
+use futures::future::Either;
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::service::{service_fn, Service};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioTimer;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+/// How many connections the kernel will queue for each listener before we accept them
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// How the accept loops of a multi-acceptor server are scheduled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcceptorRuntime {
+    /// Every accept loop is a task on the runtime that started the server
+    Shared,
+    /// Every accept loop gets its own thread with a current-thread runtime.
+    /// Connections are served on the runtime of the loop that accepted them.
+    PerAcceptor,
+}
+
+/// How long an accept loop backs off after a failed accept, for example when out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections from one listener, forever, serving each connection on its own task.
+/// The tasks are handed to the supervisor, which notices panics and can wait for them on shutdown.
+/// Every connection is served with the config that is current when it is accepted.
+pub async fn accept_loop<L, S>(listener: L, service: S, server_stats: Arc<ServerStats>, supervisor: ConnectionSupervisor, config: SharedConfig)
+where
+    L: Listener,
+    S: Service<Request<Incoming>, Response=Response<String>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Failing to accept one connection should not take the listener down with it
+                tracing::warn!("Failed to accept a connection: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let config = config.current();
+        let live = supervisor.live_connections();
+        if config.config().max_connections.is_some_and(|max| live >= max) {
+            tracing::warn!("Refusing connection from {addr}, {live} connections are already live");
+            continue;
+        }
+        tracing::debug!("Received connection from {addr}, spawning ({live} live)");
+        supervisor.spawn(addr.clone(), serve_connection(stream, addr, service.clone(), server_stats.clone(), config));
+        // tokio::task::yield_now().await;
+    }
+}
+
+/// Serves HTTP/1 on a single accepted connection, counting its traffic into `server_stats`.
+/// The timeouts and rate limit come from `config`, which stays the same for the whole connection.
+pub async fn serve_connection<IO, S>(stream: IO, addr: PeerAddr, service: S, server_stats: Arc<ServerStats>, config: Arc<ActiveConfig>)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response=Response<String>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // The counting wrapper sits between the socket and hyper, and reports when the connection closes
+    let counting_stream = CountingIo::new(stream, addr.clone(), server_stats);
+    let connection_stats = counting_stream.stats();
+    let stream = hyper_util::rt::TokioIo::new(counting_stream);
+    let body_read_timeout = BodyReadTimeout(config.body_read_timeout());
+    let header_read_timeout = config.header_read_timeout();
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        connection_stats.record_request();
+        let rate_limiter = config.rate_limiter();
+        // Over the limit, the service is not called at all
+        if rate_limiter.is_none_or(RateLimiter::try_acquire) {
+            req.extensions_mut().insert(body_read_timeout);
+            Either::Left(service.call(req))
+        } else {
+            let response = too_many_requests(rate_limiter.map(RateLimiter::retry_after).unwrap_or_default());
+            Either::Right(async move { Ok(response) })
+        }
+    });
+    let result = hyper::server::conn::http1::Builder::new()
+        .keep_alive(false)
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_read_timeout)
+        .serve_connection(stream, service).await;
+    if let Err(e) = result {
+        tracing::warn!("Error serving {addr}: {e:?}");
+    }
+    tracing::debug!("Finished serving connection for {addr}");
+}
+
+fn too_many_requests(retry_after: Duration) -> Response<String> {
+    let mut response = MyError::TooManyRequests.into_response(None);
+    // Retry-After is in whole seconds, so round up rather than invite an immediate retry
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+/// Where the accept loops of [AcceptorRuntime::PerAcceptor] threads are in shutting down
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AcceptorPhase {
+    Accepting,
+    /// No new connections are accepted, but the runtime keeps serving the ones it has
+    Draining,
+    /// The connections are done, so the runtime and its thread can stop
+    Stopped,
+}
+
+/// A server listening on any number of addresses at once, for example a public port and an
+/// admin port, or TCP and a unix socket.
+/// Every listener has its own accept loop and its own service, but the connections from all of
+/// them share the same stats and the same supervisor.
+pub struct Server {
+    server_stats: Arc<ServerStats>,
+    supervisor: ConnectionSupervisor,
+    config: SharedConfig,
+    accept_loops: JoinSet<()>,
+    acceptor_threads: Vec<JoinHandle<()>>,
+    acceptor_phase: watch::Sender<AcceptorPhase>,
+    // Every acceptor thread holds a clone until it has stopped accepting, so the receiver sees
+    // the channel close once they all have
+    accepting: mpsc::Sender<()>,
+    stopped_accepting: mpsc::Receiver<()>,
+}
+
+impl Server {
+    /// Starts a server with no listeners yet and the default config, on the current runtime
+    pub fn start() -> Self {
+        Server::start_with_config(SharedConfig::default())
+    }
+
+    /// Starts a server with no listeners yet, on the current runtime.
+    /// Replacing the config through `config` affects connections accepted from then on.
+    pub fn start_with_config(config: SharedConfig) -> Self {
+        let (accepting, stopped_accepting) = mpsc::channel(1);
+        Server {
+            server_stats: Arc::new(ServerStats::default()),
+            supervisor: ConnectionSupervisor::start(),
+            config,
+            accept_loops: JoinSet::new(),
+            acceptor_threads: Vec::new(),
+            acceptor_phase: watch::Sender::new(AcceptorPhase::Accepting),
+            accepting,
+            stopped_accepting,
+        }
+    }
+
+    /// Starts accepting connections from `listener`, serving them with `service`.
+    /// Returns the address the listener is bound to.
+    pub fn serve<L, S>(&mut self, listener: L, service: S) -> std::io::Result<PeerAddr>
+    where
+        L: Listener,
+        S: Service<Request<Incoming>, Response=Response<String>> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let addr = listener.local_addr()?;
+        self.accept_loops.spawn(accept_loop(listener, service, self.server_stats.clone(), self.supervisor.clone(), self.config.clone()));
+        Ok(addr)
+    }
+
+    /// Runs an accept loop for each of the listeners, all serving `service`, see [bind_reuseport].
+    /// With [AcceptorRuntime::Shared] the loops are spawned on the current runtime, just like
+    /// [Server::serve]. With [AcceptorRuntime::PerAcceptor] a thread is started per listener.
+    /// Either way, shutting the server down stops them.
+    pub fn serve_acceptors<S>(&mut self, listeners: Vec<std::net::TcpListener>, runtime: AcceptorRuntime, service: S) -> std::io::Result<()>
+    where
+        S: Service<Request<Incoming>, Response=Response<String>> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        for listener in listeners {
+            match runtime {
+                AcceptorRuntime::Shared => {
+                    self.serve(TcpListener::from_std(listener)?, service.clone())?;
+                }
+                AcceptorRuntime::PerAcceptor => {
+                    let index = self.acceptor_threads.len();
+                    let service = service.clone();
+                    let server_stats = self.server_stats.clone();
+                    let supervisor = self.supervisor.clone();
+                    let config = self.config.clone();
+                    let mut phase = self.acceptor_phase.subscribe();
+                    let accepting = self.accepting.clone();
+                    let thread = std::thread::Builder::new()
+                        .name(format!("acceptor-{index}"))
+                        .spawn(move || {
+                            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                            rt.block_on(async move {
+                                // The listener must be registered from inside the runtime that will poll it
+                                let listener = TcpListener::from_std(listener).unwrap();
+                                tokio::select! {
+                                    _ = accept_loop(listener, service, server_stats, supervisor, config) => {}
+                                    _ = phase.wait_for(|phase| *phase != AcceptorPhase::Accepting) => {}
+                                }
+                                drop(accepting);
+                                // The connections accepted here are served on this runtime, so it has to keep
+                                // running until the supervisor is done with them
+                                let _ = phase.wait_for(|phase| *phase == AcceptorPhase::Stopped).await;
+                            });
+                        })?;
+                    self.acceptor_threads.push(thread);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.server_stats.clone()
+    }
+
+    pub fn supervisor(&self) -> &ConnectionSupervisor {
+        &self.supervisor
+    }
+
+    pub fn config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    /// Stops every accept loop, then waits for or aborts the connections, depending on `mode`
+    pub async fn shutdown(self, mode: ShutdownMode) -> SupervisorReport {
+        let Server { supervisor, mut accept_loops, acceptor_threads, acceptor_phase, accepting, mut stopped_accepting, .. } = self;
+        acceptor_phase.send_replace(AcceptorPhase::Draining);
+        accept_loops.shutdown().await;
+        drop(accepting);
+        let _ = stopped_accepting.recv().await;
+
+        let report = supervisor.shutdown(mode).await;
+        acceptor_phase.send_replace(AcceptorPhase::Stopped);
+        for thread in acceptor_threads {
+            if tokio::task::spawn_blocking(move || thread.join()).await.is_err() {
+                tracing::error!("An acceptor thread panicked");
+            }
+        }
+        report
+    }
+}
+
+/// Binds `count` listeners to the same address with SO_REUSEPORT, so the kernel spreads incoming
+/// connections between them.
+/// If the port in `addr` is 0, the first listener picks a port and the others join it.
+pub fn bind_reuseport(addr: SocketAddr, count: usize) -> std::io::Result<Vec<std::net::TcpListener>> {
+    let mut listeners = Vec::with_capacity(count);
+    let mut bind_addr = addr;
+    for _ in 0..count {
+        let socket = Socket::new(Domain::for_address(bind_addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_port(true)?;
+        socket.set_reuse_address(true)?;
+        // Tokio requires listeners to be non-blocking
+        socket.set_nonblocking(true)?;
+        socket.bind(&bind_addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        let listener: std::net::TcpListener = socket.into();
+        bind_addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::AdminService;
+    use crate::config::ServerConfig;
+    use crate::good_service::GoodTowerService;
+    use crate::listener::{bind_unix, PeerAddr};
+    use crate::server::{bind_reuseport, AcceptorRuntime, Server};
+    use crate::supervisor::ShutdownMode;
+    use std::time::Duration;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+    async fn get(addr: std::net::SocketAddr) -> String {
+        get_path(TcpStream::connect(addr).await.unwrap(), "/").await
+    }
+
+    async fn get_path(mut stream: impl AsyncRead + AsyncWrite + Unpin, path: &str) -> String {
+        stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_reuseport_listeners_share_a_port() {
+        let listeners = bind_reuseport("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap().port() == port));
+    }
+
+    #[tokio::test]
+    async fn test_acceptors_serve_requests() {
+        for runtime in [AcceptorRuntime::Shared, AcceptorRuntime::PerAcceptor] {
+            let listeners = bind_reuseport("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+            let addr = listeners[0].local_addr().unwrap();
+            let mut server = Server::start();
+            server.serve_acceptors(listeners, runtime, GoodTowerService::new()).unwrap();
+            for _ in 0..4 {
+                let response = get(addr).await;
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "{runtime:?}: {response}");
+                assert!(response.ends_with("test"));
+            }
+            let report = server.shutdown(ShutdownMode::Graceful(Duration::from_secs(5))).await;
+            assert_eq!(report.completed, 4, "{runtime:?}");
+            // Shutdown stops the accept loops too, whichever runtime they run on
+            assert!(TcpStream::connect(addr).await.is_err(), "{runtime:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_stops_acceptors_first() {
+        let listeners = bind_reuseport("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let mut server = Server::start();
+        server.serve_acceptors(listeners, AcceptorRuntime::PerAcceptor, GoodTowerService::new()).unwrap();
+
+        // A request that is half sent when shutdown starts is still served, on its acceptor's runtime
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        wait_for_live(&server, 1).await;
+        let shutdown = tokio::spawn(server.shutdown(ShutdownMode::Graceful(Duration::from_secs(5))));
+        while TcpStream::connect(addr).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        slow.write_all(b"Host: test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        // Probes that got in before the loops stopped were served as well, nothing was cut off
+        let report = shutdown.await.unwrap();
+        assert!(report.completed >= 1);
+        assert_eq!((report.panicked, report.aborted), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_one_server_on_several_listeners() {
+        let mut server = Server::start();
+        let public = GoodTowerService::new();
+        let PeerAddr::Tcp(v4) = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), public.clone()).unwrap() else { panic!() };
+        // Not every host has IPv6, so that part is skipped where it cannot be bound
+        let v6 = match TcpListener::bind("[::1]:0").await {
+            Ok(listener) => {
+                let PeerAddr::Tcp(v6) = server.serve(listener, public.clone()).unwrap() else { panic!() };
+                Some(v6)
+            }
+            Err(e) => {
+                eprintln!("Skipping the IPv6 listener: {e}");
+                None
+            }
+        };
+        let path = std::env::temp_dir().join(format!("hyper-service-server-{}.sock", std::process::id()));
+        server.serve(bind_unix(&path).unwrap(), public).unwrap();
+        let admin = AdminService::new(server.stats(), server.supervisor().clone());
+        let PeerAddr::Tcp(admin_addr) = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), admin).unwrap() else { panic!() };
+
+        assert!(get(v4).await.ends_with("\r\n\r\ntest"));
+        if let Some(v6) = v6 {
+            assert!(get(v6).await.ends_with("\r\n\r\ntest"));
+        }
+        assert!(get_path(UnixStream::connect(&path).await.unwrap(), "/").await.ends_with("\r\n\r\ntest"));
+
+        // The admin routes only exist on the admin listener, and count connections from every listener
+        let public_stats = get_path(TcpStream::connect(v4).await.unwrap(), "/stats").await;
+        assert!(public_stats.ends_with("\r\n\r\ntest"), "{public_stats}");
+        let admin_stats = get_path(TcpStream::connect(admin_addr).await.unwrap(), "/stats").await;
+        assert!(admin_stats.starts_with("HTTP/1.1 200 OK"), "{admin_stats}");
+        let opened = 4 + usize::from(v6.is_some());
+        assert!(admin_stats.contains(&format!(r#""connections_opened":{opened}"#)), "{admin_stats}");
+        let admin_root = get_path(TcpStream::connect(admin_addr).await.unwrap(), "/").await;
+        assert!(admin_root.starts_with("HTTP/1.1 404"), "{admin_root}");
+
+        let report = server.shutdown(ShutdownMode::Graceful(Duration::from_secs(5))).await;
+        assert_eq!(report.completed, opened + 1);
+        // The accept loops are gone, so nobody answers any more
+        assert!(TcpStream::connect(v4).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Waits until the server has taken on `count` connections
+    async fn wait_for_live(server: &Server, count: usize) {
+        while server.supervisor().live_connections() != count {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_config_applies_to_new_connections_only() {
+        let mut server = Server::start();
+        let PeerAddr::Tcp(addr) = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), GoodTowerService::new()).unwrap() else { panic!() };
+
+        // This connection is accepted before the reload, so it is not rate limited
+        let mut old = TcpStream::connect(addr).await.unwrap();
+        old.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        wait_for_live(&server, 1).await;
+        let limited = ServerConfig::parse(r#"{"rate_limit": {"requests_per_second": 0.001, "burst": 1}}"#).unwrap();
+        assert!(server.config().replace(limited).unwrap());
+
+        assert!(get(addr).await.starts_with("HTTP/1.1 200 OK"));
+        let rejected = get(addr).await;
+        assert!(rejected.starts_with("HTTP/1.1 429"), "{rejected}");
+        assert!(rejected.contains("retry-after: "), "{rejected}");
+        old.write_all(b"Host: test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        old.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        // An invalid config is refused, and connections over the cap are closed straight away
+        assert!(server.config().replace(ServerConfig { max_connections: Some(0), ..Default::default() }).is_err());
+        assert!(server.config().replace(ServerConfig { max_connections: Some(1), ..Default::default() }).unwrap());
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        wait_for_live(&server, 1).await;
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        // The connection is closed without a response, or reset
+        assert!(refused.read_to_end(&mut response).await.map_or(true, |read| read == 0));
+        server.shutdown(ShutdownMode::Abort).await;
+    }
+}
@@ -0,0 +1,190 @@
+use hyper::header::{IF_MATCH, IF_NONE_MATCH};
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::future::{ready, Future};
+use std::sync::Mutex;
+
+/// A value as it is held in a store, together with the entity tag identifying this version of it
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredValue {
+    pub value: String,
+    /// A strong entity tag, including the surrounding quotes, so it can be used as a header directly
+    pub etag: String,
+}
+
+/// The result of a successful write
+#[derive(Clone, Debug, PartialEq)]
+pub struct PutOutcome {
+    pub etag: String,
+    /// False if an existing value was replaced
+    pub created: bool,
+}
+
+/// Returned by a store when the precondition of a write did not hold
+#[derive(Debug, PartialEq)]
+pub struct PreconditionFailed;
+
+/// The entity tags listed in an `If-Match` or `If-None-Match` header
+#[derive(Clone, Debug, PartialEq)]
+pub enum EtagCondition {
+    /// `*`, which matches any current value
+    Any,
+    Tags(Vec<String>),
+}
+
+impl EtagCondition {
+    fn parse(header: &str) -> Self {
+        if header.trim() == "*" {
+            return EtagCondition::Any;
+        }
+        EtagCondition::Tags(header.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect())
+    }
+
+    /// Strong comparison, as required for `If-Match`: weak tags never match
+    fn matches_strong(&self, current: &str) -> bool {
+        match self {
+            EtagCondition::Any => true,
+            EtagCondition::Tags(tags) => tags.iter().any(|tag| !tag.starts_with("W/") && tag == current),
+        }
+    }
+
+    /// Weak comparison, as required for `If-None-Match`: the weakness indicator is ignored
+    fn matches_weak(&self, current: &str) -> bool {
+        match self {
+            EtagCondition::Any => true,
+            EtagCondition::Tags(tags) => tags.iter().any(|tag| tag.trim_start_matches("W/") == current.trim_start_matches("W/")),
+        }
+    }
+}
+
+/// The conditional headers of a request
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Precondition {
+    pub if_match: Option<EtagCondition>,
+    pub if_none_match: Option<EtagCondition>,
+}
+
+impl Precondition {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let condition = |name| headers.get(name)
+            .map(|value| EtagCondition::parse(&String::from_utf8_lossy(value.as_bytes())));
+        Precondition {
+            if_match: condition(IF_MATCH),
+            if_none_match: condition(IF_NONE_MATCH),
+        }
+    }
+
+    /// Whether `If-Match` holds for the current entity tag, where `None` means there is no value
+    pub fn if_match_holds(&self, current: Option<&str>) -> bool {
+        match (&self.if_match, current) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(condition), Some(current)) => condition.matches_strong(current),
+        }
+    }
+
+    /// Whether `If-None-Match` holds for the current entity tag, where `None` means there is no value
+    pub fn if_none_match_holds(&self, current: Option<&str>) -> bool {
+        match (&self.if_none_match, current) {
+            (None, _) | (Some(_), None) => true,
+            (Some(condition), Some(current)) => !condition.matches_weak(current),
+        }
+    }
+
+    pub fn holds(&self, current: Option<&str>) -> bool {
+        self.if_match_holds(current) && self.if_none_match_holds(current)
+    }
+}
+
+/// Storage behind the key-value resource.
+/// Writes take the precondition so that a store can check it and write atomically.
+pub trait KvStore: Send + Sync + 'static {
+    fn get(&self, key: &str) -> impl Future<Output=Option<StoredValue>> + Send;
+    fn put(&self, key: &str, value: String, precondition: &Precondition) -> impl Future<Output=Result<PutOutcome, PreconditionFailed>> + Send;
+    /// Returns the removed value, or `None` if there was nothing to remove
+    fn delete(&self, key: &str, precondition: &Precondition) -> impl Future<Output=Result<Option<StoredValue>, PreconditionFailed>> + Send;
+}
+
+/// A store that keeps everything in a HashMap.
+/// Entity tags come from a counter that increases with every write, so they are never reused.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    values: HashMap<String, StoredValue>,
+    version: u64,
+}
+
+impl MemoryStore {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&self, key: &str) -> impl Future<Output=Option<StoredValue>> + Send {
+        ready(self.state().values.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: String, precondition: &Precondition) -> impl Future<Output=Result<PutOutcome, PreconditionFailed>> + Send {
+        let mut state = self.state();
+        let current = state.values.get(key).map(|stored| stored.etag.as_str());
+        if !precondition.holds(current) {
+            return ready(Err(PreconditionFailed));
+        }
+        state.version += 1;
+        let etag = format!("\"{}\"", state.version);
+        let previous = state.values.insert(key.to_string(), StoredValue { value, etag: etag.clone() });
+        ready(Ok(PutOutcome { etag, created: previous.is_none() }))
+    }
+
+    fn delete(&self, key: &str, precondition: &Precondition) -> impl Future<Output=Result<Option<StoredValue>, PreconditionFailed>> + Send {
+        let mut state = self.state();
+        let current = state.values.get(key).map(|stored| stored.etag.as_str());
+        if !precondition.holds(current) {
+            return ready(Err(PreconditionFailed));
+        }
+        ready(Ok(state.values.remove(key)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::{EtagCondition, KvStore, MemoryStore, Precondition, PreconditionFailed};
+
+    #[test]
+    fn test_etag_conditions() {
+        assert_eq!(EtagCondition::parse(" * "), EtagCondition::Any);
+        let condition = EtagCondition::parse(r#""1", W/"2""#);
+        assert!(condition.matches_strong(r#""1""#));
+        assert!(!condition.matches_strong(r#""2""#));
+        assert!(condition.matches_weak(r#""2""#));
+        assert!(!condition.matches_weak(r#""3""#));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_checks_preconditions() {
+        let store = MemoryStore::default();
+        let create_only = Precondition { if_none_match: Some(EtagCondition::Any), ..Default::default() };
+
+        let first = store.put("key", "one".to_string(), &create_only).await.unwrap();
+        assert!(first.created);
+        assert_eq!(store.put("key", "two".to_string(), &create_only).await, Err(PreconditionFailed));
+
+        let stale = Precondition { if_match: Some(EtagCondition::Tags(vec![r#""0""#.to_string()])), ..Default::default() };
+        assert_eq!(store.delete("key", &stale).await, Err(PreconditionFailed));
+
+        let current = Precondition { if_match: Some(EtagCondition::Tags(vec![first.etag.clone()])), ..Default::default() };
+        let second = store.put("key", "two".to_string(), &current).await.unwrap();
+        assert!(!second.created);
+        assert_ne!(second.etag, first.etag);
+        assert_eq!(store.get("key").await.unwrap().value, "two");
+
+        assert_eq!(store.delete("key", &Precondition::default()).await.unwrap().unwrap().value, "two");
+        assert_eq!(store.get("key").await, None);
+        assert_eq!(store.delete("key", &Precondition::default()).await, Ok(None));
+    }
+}
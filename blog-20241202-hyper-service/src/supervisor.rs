@@ -0,0 +1,206 @@
+use crate::listener::PeerAddr;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{Id, JoinError, JoinSet};
+
+/// How the supervisor treats connections that are still running when it shuts down
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownMode {
+    /// Wait for every connection to finish, aborting whatever is left once the timeout passes
+    Graceful(Duration),
+    /// Abort every connection straight away
+    Abort,
+}
+
+/// What happened to the connection tasks over the lifetime of the supervisor
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SupervisorReport {
+    pub completed: usize,
+    pub panicked: usize,
+    pub aborted: usize,
+}
+
+enum Command {
+    Spawn {
+        peer: PeerAddr,
+        task: BoxFuture<'static, ()>,
+        runtime: Handle,
+    },
+    Shutdown {
+        mode: ShutdownMode,
+        done: oneshot::Sender<SupervisorReport>,
+    },
+}
+
+/// Keeps track of every connection task, so that panics are noticed and shutdown can wait for them.
+/// The tasks are held in a JoinSet owned by a background task; this is a cheap handle to it that
+/// can be cloned into every accept loop.
+#[derive(Clone)]
+pub struct ConnectionSupervisor {
+    commands: mpsc::UnboundedSender<Command>,
+    live: Arc<AtomicUsize>,
+}
+
+impl ConnectionSupervisor {
+    /// Starts the supervisor on the current runtime
+    pub fn start() -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let live = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(supervise(receiver, live.clone()));
+        ConnectionSupervisor { commands, live }
+    }
+
+    /// Spawns a connection task on the runtime of the caller, and hands it to the supervisor.
+    /// Once the supervisor has shut down, the task is dropped instead.
+    pub fn spawn<F>(&self, peer: PeerAddr, task: F)
+    where
+        F: Future<Output=()> + Send + 'static,
+    {
+        self.live.fetch_add(1, Ordering::Relaxed);
+        let command = Command::Spawn { peer, task: Box::pin(task), runtime: Handle::current() };
+        if let Err(mpsc::error::SendError(command)) = self.commands.send(command) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            if let Command::Spawn { peer, .. } = command {
+                tracing::warn!("Supervisor has shut down, dropping connection from {peer}");
+            }
+        }
+    }
+
+    /// How many connection tasks have been spawned and have not finished yet
+    pub fn live_connections(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting new tasks and waits for, or aborts, the ones still running
+    pub async fn shutdown(&self, mode: ShutdownMode) -> SupervisorReport {
+        let (done, report) = oneshot::channel();
+        if self.commands.send(Command::Shutdown { mode, done }).is_err() {
+            return SupervisorReport::default();
+        }
+        report.await.unwrap_or_default()
+    }
+}
+
+struct Supervisor {
+    tasks: JoinSet<()>,
+    peers: HashMap<Id, PeerAddr>,
+    live: Arc<AtomicUsize>,
+    report: SupervisorReport,
+}
+
+impl Supervisor {
+    fn reap(&mut self, result: Result<(Id, ()), JoinError>) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        let id = match &result {
+            Ok((id, ())) => *id,
+            Err(e) => e.id(),
+        };
+        let peer = self.peers.remove(&id);
+        match result {
+            Ok(_) => self.report.completed += 1,
+            Err(e) if e.is_panic() => {
+                self.report.panicked += 1;
+                let payload = e.into_panic();
+                let message = payload.downcast_ref::<&str>().copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                match peer {
+                    Some(peer) => tracing::error!("Connection task for {peer} panicked: {message}"),
+                    None => tracing::error!("Connection task panicked: {message}"),
+                }
+            }
+            Err(_) => self.report.aborted += 1,
+        }
+    }
+}
+
+async fn supervise(mut commands: mpsc::UnboundedReceiver<Command>, live: Arc<AtomicUsize>) {
+    let mut supervisor = Supervisor {
+        tasks: JoinSet::new(),
+        peers: HashMap::new(),
+        live,
+        report: SupervisorReport::default(),
+    };
+    let (mode, done) = loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Spawn { peer, task, runtime }) => {
+                    let abort_handle = supervisor.tasks.spawn_on(task, &runtime);
+                    supervisor.peers.insert(abort_handle.id(), peer);
+                }
+                Some(Command::Shutdown { mode, done }) => break (mode, Some(done)),
+                // Every handle is gone, so nobody can ask for a report; we still let the tasks finish
+                None => break (ShutdownMode::Graceful(Duration::MAX), None),
+            },
+            Some(result) = supervisor.tasks.join_next_with_id() => supervisor.reap(result),
+        }
+    };
+    // No new tasks are accepted from here on
+    commands.close();
+    while let Ok(Command::Spawn { peer, .. }) = commands.try_recv() {
+        supervisor.live.fetch_sub(1, Ordering::Relaxed);
+        tracing::warn!("Supervisor is shutting down, dropping connection from {peer}");
+    }
+
+    if let ShutdownMode::Graceful(timeout) = mode {
+        let drain = async {
+            while let Some(result) = supervisor.tasks.join_next_with_id().await {
+                supervisor.reap(result);
+            }
+        };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            tracing::warn!("{} connections did not finish in time, aborting them", supervisor.tasks.len());
+        }
+    }
+    supervisor.tasks.abort_all();
+    while let Some(result) = supervisor.tasks.join_next_with_id().await {
+        supervisor.reap(result);
+    }
+    if let Some(done) = done {
+        let _ = done.send(supervisor.report);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::supervisor::{ConnectionSupervisor, ShutdownMode, SupervisorReport};
+    use crate::listener::PeerAddr;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reaps_panics_and_drains_on_shutdown() {
+        let supervisor = ConnectionSupervisor::start();
+        let peer = PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap());
+
+        supervisor.spawn(peer.clone(), async {});
+        supervisor.spawn(peer.clone(), async { panic!("connection handler bug") });
+        supervisor.spawn(peer, tokio::time::sleep(Duration::from_millis(50)));
+        assert_eq!(supervisor.live_connections(), 3);
+
+        let report = supervisor.shutdown(ShutdownMode::Graceful(Duration::from_secs(5))).await;
+        assert_eq!(report, SupervisorReport { completed: 2, panicked: 1, aborted: 0 });
+        assert_eq!(supervisor.live_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_shutdown() {
+        let supervisor = ConnectionSupervisor::start();
+        let peer = PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap());
+
+        supervisor.spawn(peer.clone(), std::future::pending());
+        supervisor.spawn(peer.clone(), std::future::pending());
+        let report = supervisor.shutdown(ShutdownMode::Graceful(Duration::from_millis(10))).await;
+        assert_eq!(report, SupervisorReport { completed: 0, panicked: 0, aborted: 2 });
+
+        // Once shut down, new connections are not run
+        supervisor.spawn(peer, async { panic!("must not run") });
+        assert_eq!(supervisor.live_connections(), 0);
+        assert_eq!(supervisor.shutdown(ShutdownMode::Abort).await, SupervisorReport::default());
+    }
+}
@@ -0,0 +1,75 @@
+use blog_20241202_hyper_service::bad_service::BadTowerService;
+use blog_20241202_hyper_service::comparison::{compare, observe, scenarios, Observed, Report};
+use blog_20241202_hyper_service::good_service::GoodTowerService;
+use blog_20241202_hyper_service::listener::PeerAddr;
+use blog_20241202_hyper_service::server::Server;
+use blog_20241202_hyper_service::supervisor::ShutdownMode;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Both services on one server, each on its own port
+async fn start() -> (Server, SocketAddr, SocketAddr) {
+    let mut server = Server::start();
+    let bad = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), Arc::new(BadTowerService {})).unwrap();
+    let good = server.serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), GoodTowerService::new()).unwrap();
+    let (PeerAddr::Tcp(bad), PeerAddr::Tcp(good)) = (bad, good) else { panic!() };
+    (server, bad, good)
+}
+
+fn status(observed: &Observed) -> Option<u16> {
+    match observed {
+        Observed::Response { status, .. } => Some(*status),
+        Observed::NoResponse(_) => None,
+    }
+}
+
+#[tokio::test]
+async fn test_scenario_matrix() {
+    // Scenario, then the status from each service; None means the connection closed without a response
+    let expected: &[(&str, Option<u16>, Option<u16>)] = &[
+        ("GET /", Some(200), Some(200)),
+        ("POST text is echoed", None, Some(200)),
+        ("POST without a body", None, Some(200)),
+        ("POST invalid UTF-8", None, Some(400)),
+        ("POST unsupported content type", None, Some(415)),
+        ("PUT / is not allowed", None, Some(405)),
+        ("DELETE / is not allowed", None, Some(405)),
+        ("HEAD /", None, Some(200)),
+        ("OPTIONS /", None, Some(204)),
+        ("GET a missing key", Some(200), Some(404)),
+        ("PUT a key", None, Some(201)),
+    ];
+    assert_eq!(scenarios().len(), expected.len());
+
+    let (server, bad, good) = start().await;
+    for (scenario, (name, bad_status, good_status)) in scenarios().iter().zip(expected) {
+        assert_eq!(scenario.name, *name);
+        assert_eq!(status(&observe(bad, scenario).await), *bad_status, "bad service, {name}");
+        assert_eq!(status(&observe(good, scenario).await), *good_status, "good service, {name}");
+    }
+    let report = server.shutdown(ShutdownMode::Abort).await;
+    // A service error closes the connection, but it never takes the connection task down
+    assert_eq!(report.panicked, 0);
+}
+
+#[tokio::test]
+async fn test_report_shows_post_handling_and_error_surfaces() {
+    let (server, bad, good) = start().await;
+    let differences = compare(bad, good).await;
+    server.shutdown(ShutdownMode::Abort).await;
+
+    let echo = differences.iter().find(|difference| difference.scenario == "POST text is echoed").unwrap();
+    assert!(matches!(echo.bad, Observed::NoResponse(_)));
+    assert!(matches!(&echo.good, Observed::Response { status: 200, body, .. } if body == "hello"));
+
+    // The good service explains its errors, where the bad one just hangs up
+    let not_allowed = differences.iter().find(|difference| difference.scenario == "PUT / is not allowed").unwrap();
+    assert!(matches!(not_allowed.bad, Observed::NoResponse(_)));
+    let Observed::Response { allow, body, .. } = &not_allowed.good else { panic!() };
+    assert_eq!(allow.as_deref(), Some("GET, POST, HEAD, OPTIONS"));
+    assert!(body.contains(r#""error":"method_not_allowed""#));
+
+    let report = Report(differences).to_string();
+    assert!(report.starts_with("11 of 11 scenarios behave differently"), "{report}");
+}
@@ -1,9 +1,35 @@
+use crate::framing::FrameError;
+use crate::pattern_handler::HandlerError;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::Duration;
+use tokio::task::JoinError;
+
+#[allow(clippy::enum_variant_names)]
 /// An error type that captures errors from the layer but retains errors from the inner service
 #[derive(Debug)]
 pub enum LayerError<E> {
     #[allow(unused)]
     ServiceLayerError(&'static str),
     InnerError(E),
+    /// The bytes passing through the layer did not follow the framing it was configured with
+    Framing(FrameError),
+    /// A handler failed, or closed before the exchange was over
+    Handler(HandlerError),
+    /// Reading or writing failed; `context` says which side it was
+    Io { context: &'static str, source: io::Error },
+    /// The other end of a channel went away while a reply was still needed.
+    /// The layers end a session cleanly when either side closes, so this is for the services around them.
+    #[allow(unused)]
+    ChannelClosed,
+    /// The task running the inner service panicked or was cancelled.
+    /// If it panicked, the payload can be recovered with [JoinError::into_panic].
+    TaskJoin(JoinError),
+    /// The session took longer than the layer allows
+    Timeout(Duration),
+    /// A request could not be converted into what the inner service accepts
+    Conversion(Box<dyn Error + Send + Sync>),
 }
 
 impl<E> From<E> for LayerError<E> {
@@ -12,3 +38,59 @@ impl<E> From<E> for LayerError<E> {
     }
 }
 
+// The causes are left to `source`, so that they are not printed twice when the chain is reported
+impl<E> Display for LayerError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerError::ServiceLayerError(reason) => write!(f, "{reason}"),
+            LayerError::InnerError(_) => write!(f, "inner service failed"),
+            LayerError::Framing(_) => write!(f, "invalid framing"),
+            LayerError::Handler(_) => write!(f, "handler failed"),
+            LayerError::Io { context, .. } => write!(f, "{context}"),
+            LayerError::ChannelClosed => write!(f, "channel closed"),
+            LayerError::TaskJoin(_) => write!(f, "inner service task did not complete"),
+            LayerError::Timeout(duration) => write!(f, "timed out after {duration:?}"),
+            LayerError::Conversion(_) => write!(f, "request conversion failed"),
+        }
+    }
+}
+
+impl<E> Error for LayerError<E>
+where
+    E: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LayerError::InnerError(e) => Some(e),
+            LayerError::Framing(e) => Some(e),
+            LayerError::Handler(e) => Some(e),
+            LayerError::Io { source, .. } => Some(source),
+            LayerError::TaskJoin(e) => Some(e),
+            LayerError::Conversion(e) => Some(e.as_ref()),
+            LayerError::ServiceLayerError(_) | LayerError::ChannelClosed | LayerError::Timeout(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::framing::FrameError;
+    use std::error::Error;
+    use std::io;
+
+    #[test]
+    fn test_source_chain() {
+        let error: LayerError<io::Error> = LayerError::InnerError(io::Error::other("disk on fire"));
+        assert_eq!(error.to_string(), "inner service failed");
+        assert_eq!(error.source().unwrap().to_string(), "disk on fire");
+
+        let error: LayerError<io::Error> = LayerError::Framing(FrameError::Malformed("not utf-8"));
+        assert_eq!(error.source().unwrap().to_string(), "malformed frame: not utf-8");
+
+        // It can go anywhere an error can
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(LayerError::<io::Error>::ChannelClosed);
+        assert_eq!(boxed.to_string(), "channel closed");
+        assert!(boxed.source().is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt::{Display, Formatter};
+
+/// Why a stream of bytes could not be split into frames, or a frame could not be sent
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    /// A frame is larger than the codec allows
+    TooLarge { size: usize, max: usize },
+    /// The stream ended part way through a frame
+    Truncated { remaining: usize },
+    /// A fixed-size codec was asked to send a frame of another size
+    WrongSize { size: usize, expected: usize },
+    /// A whole frame arrived, but it does not hold a valid message
+    Malformed(&'static str),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => write!(f, "frame of {size} bytes is larger than the maximum of {max}"),
+            FrameError::Truncated { remaining } => write!(f, "stream ended with {remaining} bytes of an incomplete frame"),
+            FrameError::WrongSize { size, expected } => write!(f, "frame of {size} bytes, expected exactly {expected}"),
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Splits a stream of bytes into frames, and turns frames back into bytes.
+/// Each direction of each session gets its own clone of the codec.
+pub trait FrameCodec: Clone + Send + 'static {
+    /// Takes the first complete frame off the front of `buffer`, if it has fully arrived
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError>;
+
+    /// Turns a frame into the bytes to send
+    fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, FrameError>;
+
+    /// Called once the stream has ended, with whatever is left after the last complete frame
+    fn decode_eof(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        match self.decode(buffer)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buffer.is_empty() => Ok(None),
+            None => Err(FrameError::Truncated { remaining: buffer.len() }),
+        }
+    }
+}
+
+/// No framing: whatever a single read returns is a frame.
+/// This is only safe when every message is small enough to arrive in one read.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Raw;
+
+impl FrameCodec for Raw {
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        Ok((!buffer.is_empty()).then(|| std::mem::take(buffer)))
+    }
+
+    fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        Ok(frame)
+    }
+}
+
+/// Every frame is preceded by its length, as a 4 byte big-endian integer
+#[derive(Clone, Copy, Debug)]
+pub struct LengthDelimited {
+    max_frame_size: usize,
+}
+
+impl LengthDelimited {
+    const HEADER_SIZE: usize = 4;
+
+    pub fn new(max_frame_size: usize) -> Self {
+        LengthDelimited { max_frame_size }
+    }
+}
+
+impl FrameCodec for LengthDelimited {
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(header) = buffer.first_chunk::<{ Self::HEADER_SIZE }>() else {
+            return Ok(None);
+        };
+        let size = u32::from_be_bytes(*header) as usize;
+        // We know the size before the frame arrives, so we refuse it before buffering it
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge { size, max: self.max_frame_size });
+        }
+        if buffer.len() < Self::HEADER_SIZE + size {
+            return Ok(None);
+        }
+        let rest = buffer.split_off(Self::HEADER_SIZE + size);
+        let mut frame = std::mem::replace(buffer, rest);
+        frame.drain(..Self::HEADER_SIZE);
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        if frame.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge { size: frame.len(), max: self.max_frame_size });
+        }
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE + frame.len());
+        bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        bytes.extend(frame);
+        Ok(bytes)
+    }
+}
+
+/// Every frame ends with `\n`, which is not part of the frame
+#[derive(Clone, Copy, Debug)]
+pub struct NewlineDelimited {
+    max_frame_size: usize,
+}
+
+impl NewlineDelimited {
+    pub fn new(max_frame_size: usize) -> Self {
+        NewlineDelimited { max_frame_size }
+    }
+}
+
+impl FrameCodec for NewlineDelimited {
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(end) = buffer.iter().position(|byte| *byte == b'\n') else {
+            // Without a newline in sight, the frame is at least as long as what we have
+            if buffer.len() > self.max_frame_size {
+                return Err(FrameError::TooLarge { size: buffer.len(), max: self.max_frame_size });
+            }
+            return Ok(None);
+        };
+        if end > self.max_frame_size {
+            return Err(FrameError::TooLarge { size: end, max: self.max_frame_size });
+        }
+        let rest = buffer.split_off(end + 1);
+        let mut frame = std::mem::replace(buffer, rest);
+        frame.pop();
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        if frame.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge { size: frame.len(), max: self.max_frame_size });
+        }
+        frame.push(b'\n');
+        Ok(frame)
+    }
+}
+
+/// Every frame is exactly the same size, so no delimiter is needed
+#[derive(Clone, Copy, Debug)]
+pub struct FixedSize {
+    size: usize,
+}
+
+impl FixedSize {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "fixed size frames cannot be empty");
+        FixedSize { size }
+    }
+}
+
+impl FrameCodec for FixedSize {
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        if buffer.len() < self.size {
+            return Ok(None);
+        }
+        let rest = buffer.split_off(self.size);
+        Ok(Some(std::mem::replace(buffer, rest)))
+    }
+
+    fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        if frame.len() != self.size {
+            return Err(FrameError::WrongSize { size: frame.len(), expected: self.size });
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::framing::{FixedSize, FrameCodec, FrameError, LengthDelimited, NewlineDelimited};
+
+    /// Feeds `bytes` in one byte at a time, collecting every frame that comes out
+    fn decode_all(codec: &mut impl FrameCodec, bytes: &[u8]) -> Result<Vec<Vec<u8>>, FrameError> {
+        let mut buffer = Vec::new();
+        let mut frames = Vec::new();
+        for byte in bytes {
+            buffer.push(*byte);
+            while let Some(frame) = codec.decode(&mut buffer)? {
+                frames.push(frame);
+            }
+        }
+        frames.extend(codec.decode_eof(&mut buffer)?);
+        Ok(frames)
+    }
+
+    #[test]
+    fn test_frames_survive_any_split() {
+        let mut length = LengthDelimited::new(16);
+        let mut bytes = length.encode(b"hello".to_vec()).unwrap();
+        bytes.extend(length.encode(b"".to_vec()).unwrap());
+        bytes.extend(length.encode(b"world".to_vec()).unwrap());
+        assert_eq!(decode_all(&mut length, &bytes).unwrap(), [b"hello".to_vec(), vec![], b"world".to_vec()]);
+
+        let mut newline = NewlineDelimited::new(16);
+        assert_eq!(decode_all(&mut newline, b"hello\nworld\n").unwrap(), [b"hello".to_vec(), b"world".to_vec()]);
+        assert_eq!(newline.encode(b"hello".to_vec()).unwrap(), b"hello\n");
+
+        let mut fixed = FixedSize::new(3);
+        assert_eq!(decode_all(&mut fixed, b"abcdef").unwrap(), [b"abc".to_vec(), b"def".to_vec()]);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        // The length header alone is enough to refuse a frame
+        let mut length = LengthDelimited::new(4);
+        assert_eq!(decode_all(&mut length, &[0, 0, 0, 5]), Err(FrameError::TooLarge { size: 5, max: 4 }));
+        assert_eq!(length.encode(b"hello".to_vec()), Err(FrameError::TooLarge { size: 5, max: 4 }));
+        assert_eq!(decode_all(&mut length, &[0, 0, 0, 3, b'a']), Err(FrameError::Truncated { remaining: 5 }));
+
+        // A line that never ends is refused once it is longer than the maximum
+        let mut newline = NewlineDelimited::new(4);
+        assert_eq!(decode_all(&mut newline, b"hello"), Err(FrameError::TooLarge { size: 5, max: 4 }));
+        assert_eq!(decode_all(&mut newline, b"ok\nunfinished"), Err(FrameError::TooLarge { size: 5, max: 4 }));
+
+        let mut fixed = FixedSize::new(3);
+        assert_eq!(decode_all(&mut fixed, b"abcd"), Err(FrameError::Truncated { remaining: 1 }));
+        assert_eq!(fixed.encode(b"ab".to_vec()), Err(FrameError::WrongSize { size: 2, expected: 3 }));
+    }
+}
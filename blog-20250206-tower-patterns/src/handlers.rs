@@ -0,0 +1,415 @@
+use crate::framing::{FrameCodec, FrameError};
+use crate::pattern_bridge::{MessageDecoder, MessageEncoder};
+use crate::pattern_handler::ServiceHandler;
+use crate::pattern_io::MAX_BUF_SIZE;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+
+// The layers clone their handlers for every call, so every handler here shares its state between
+// clones. Closing one clone closes them all, which is what the peer would expect.
+
+/// Why one of the standard handlers could not send or receive
+#[derive(Debug)]
+pub enum StandardHandlerError {
+    /// This side has been closed, or the peer is no longer receiving
+    Closed,
+    /// A broadcast receiver fell behind, and this many messages were skipped.
+    /// Receiving again carries on from the oldest message still available.
+    Lagged(u64),
+    Io(io::Error),
+    Frame(FrameError),
+}
+
+impl Display for StandardHandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StandardHandlerError::Closed => write!(f, "handler is closed"),
+            StandardHandlerError::Lagged(skipped) => write!(f, "handler fell behind and skipped {skipped} messages"),
+            StandardHandlerError::Io(e) => write!(f, "handler I/O failed: {e}"),
+            StandardHandlerError::Frame(e) => write!(f, "handler framing failed: {e}"),
+        }
+    }
+}
+
+impl Error for StandardHandlerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StandardHandlerError::Io(e) => Some(e),
+            StandardHandlerError::Frame(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Sends and receives over a tokio mpsc pair
+pub struct ChannelHandler<Message> {
+    sender: Arc<Mutex<Option<mpsc::Sender<Message>>>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+    // Lets close() stop sends that are still waiting for room in the channel
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl<Message> ChannelHandler<Message> {
+    pub fn new(sender: mpsc::Sender<Message>, receiver: mpsc::Receiver<Message>) -> Self {
+        ChannelHandler {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: Arc::new(Mutex::new(receiver)),
+            closed: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl<Message> Clone for ChannelHandler<Message> {
+    fn clone(&self) -> Self {
+        ChannelHandler { sender: self.sender.clone(), receiver: self.receiver.clone(), closed: self.closed.clone() }
+    }
+}
+
+impl<Message> ServiceHandler<Message> for ChannelHandler<Message>
+where
+    Message: Send,
+{
+    type Error = StandardHandlerError;
+
+    /// Waits for room in the channel, unless any clone is closed in the meantime
+    async fn send_message(&mut self, msg: Message) -> Result<(), StandardHandlerError> {
+        // The lock is not held while waiting, so that other clones can still close
+        let sender = self.sender.lock().await.clone().ok_or(StandardHandlerError::Closed)?;
+        let mut closed = self.closed.subscribe();
+        tokio::select! {
+            biased;
+            _ = closed.wait_for(|closed| *closed) => Err(StandardHandlerError::Closed),
+            result = sender.send(msg) => result.map_err(|_| StandardHandlerError::Closed),
+        }
+    }
+
+    async fn receive_message(&mut self) -> Result<Option<Message>, StandardHandlerError> {
+        Ok(self.receiver.lock().await.recv().await)
+    }
+
+    /// Drops the sender, so the peer sees the end of the stream once it has received everything
+    async fn close(&mut self) -> Result<(), StandardHandlerError> {
+        self.sender.lock().await.take();
+        self.closed.send_replace(true);
+        Ok(())
+    }
+}
+
+/// Sends and receives framed messages over anything that is `AsyncRead + AsyncWrite`, such as a socket.
+/// `Framing` splits the bytes into frames, and `Codec` turns frames into messages and back.
+pub struct StreamHandler<Stream, Framing, Codec> {
+    reader: Arc<Mutex<StreamReader<Stream, Framing, Codec>>>,
+    writer: Arc<Mutex<Option<StreamWriter<Stream, Framing, Codec>>>>,
+}
+
+struct StreamReader<Stream, Framing, Codec> {
+    reader: ReadHalf<Stream>,
+    // Bytes that have been read but do not make up a whole frame yet
+    pending: Vec<u8>,
+    framing: Framing,
+    codec: Codec,
+}
+
+struct StreamWriter<Stream, Framing, Codec> {
+    writer: WriteHalf<Stream>,
+    framing: Framing,
+    codec: Codec,
+}
+
+impl<Stream, Framing, Codec> StreamHandler<Stream, Framing, Codec>
+where
+    Stream: AsyncRead + AsyncWrite,
+    Framing: FrameCodec,
+    Codec: Clone,
+{
+    pub fn new(stream: Stream, framing: Framing, codec: Codec) -> Self {
+        // Reading and writing are locked separately, so one clone can wait for a message while another sends
+        let (reader, writer) = split(stream);
+        StreamHandler {
+            reader: Arc::new(Mutex::new(StreamReader { reader, pending: Vec::new(), framing: framing.clone(), codec: codec.clone() })),
+            writer: Arc::new(Mutex::new(Some(StreamWriter { writer, framing, codec }))),
+        }
+    }
+}
+
+impl<Stream, Framing, Codec> Clone for StreamHandler<Stream, Framing, Codec> {
+    fn clone(&self) -> Self {
+        StreamHandler { reader: self.reader.clone(), writer: self.writer.clone() }
+    }
+}
+
+impl<Stream, Framing, Codec, Message> ServiceHandler<Message> for StreamHandler<Stream, Framing, Codec>
+where
+    Stream: AsyncRead + AsyncWrite + Send + 'static,
+    Framing: FrameCodec,
+    Codec: MessageEncoder<Message> + MessageDecoder<Message>,
+    Message: Send,
+{
+    type Error = StandardHandlerError;
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), StandardHandlerError> {
+        let mut writer = self.writer.lock().await;
+        let StreamWriter { writer, framing, codec } = writer.as_mut().ok_or(StandardHandlerError::Closed)?;
+        let bytes = framing.encode(codec.encode(msg)).map_err(StandardHandlerError::Frame)?;
+        writer.write_all(&bytes).await.map_err(StandardHandlerError::Io)
+    }
+
+    async fn receive_message(&mut self) -> Result<Option<Message>, StandardHandlerError> {
+        let mut reader = self.reader.lock().await;
+        let StreamReader { reader, pending, framing, codec } = &mut *reader;
+        let mut chunk = [0u8; MAX_BUF_SIZE];
+        loop {
+            if let Some(frame) = framing.decode(pending).map_err(StandardHandlerError::Frame)? {
+                return codec.decode(frame).map(Some).map_err(StandardHandlerError::Frame);
+            }
+            let sz = reader.read(&mut chunk).await.map_err(StandardHandlerError::Io)?;
+            if sz == 0 {
+                let frame = framing.decode_eof(pending).map_err(StandardHandlerError::Frame)?;
+                return frame.map(|frame| codec.decode(frame)).transpose().map_err(StandardHandlerError::Frame);
+            }
+            pending.extend_from_slice(&chunk[..sz]);
+        }
+    }
+
+    /// Shuts down the write side of the stream, so the peer reads EOF but can still reply
+    async fn close(&mut self) -> Result<(), StandardHandlerError> {
+        match self.writer.lock().await.take() {
+            Some(mut writer) => writer.writer.shutdown().await.map_err(StandardHandlerError::Io),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Receives from a broadcast channel, so every subscriber sees every message, and sends to another one.
+/// Clones share one subscription; subscribe again to get a handler that receives on its own.
+pub struct BroadcastHandler<Message> {
+    sender: Arc<Mutex<Option<broadcast::Sender<Message>>>>,
+    receiver: Arc<Mutex<broadcast::Receiver<Message>>>,
+}
+
+impl<Message> BroadcastHandler<Message> {
+    pub fn new(sender: broadcast::Sender<Message>, receiver: broadcast::Receiver<Message>) -> Self {
+        BroadcastHandler {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+}
+
+impl<Message> Clone for BroadcastHandler<Message> {
+    fn clone(&self) -> Self {
+        BroadcastHandler { sender: self.sender.clone(), receiver: self.receiver.clone() }
+    }
+}
+
+impl<Message> ServiceHandler<Message> for BroadcastHandler<Message>
+where
+    Message: Clone + Send,
+{
+    type Error = StandardHandlerError;
+
+    /// Fails with [StandardHandlerError::Closed] if nobody is subscribed, since the message would be lost
+    async fn send_message(&mut self, msg: Message) -> Result<(), StandardHandlerError> {
+        let sender = self.sender.lock().await;
+        let sender = sender.as_ref().ok_or(StandardHandlerError::Closed)?;
+        sender.send(msg).map(|_| ()).map_err(|_| StandardHandlerError::Closed)
+    }
+
+    async fn receive_message(&mut self) -> Result<Option<Message>, StandardHandlerError> {
+        match self.receiver.lock().await.recv().await {
+            Ok(message) => Ok(Some(message)),
+            Err(broadcast::error::RecvError::Closed) => Ok(None),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => Err(StandardHandlerError::Lagged(skipped)),
+        }
+    }
+
+    /// Drops our sender. Subscribers only see the end of the stream once every sender is gone
+    async fn close(&mut self) -> Result<(), StandardHandlerError> {
+        self.sender.lock().await.take();
+        Ok(())
+    }
+}
+
+/// A single request and a single reply over a pair of oneshot channels.
+/// After the request, receiving reports the end of the stream, and a second reply fails.
+pub struct OneshotHandler<Message> {
+    request: Arc<Mutex<Option<oneshot::Receiver<Message>>>>,
+    reply: Arc<Mutex<Option<oneshot::Sender<Message>>>>,
+}
+
+impl<Message> OneshotHandler<Message> {
+    pub fn new(request: oneshot::Receiver<Message>, reply: oneshot::Sender<Message>) -> Self {
+        OneshotHandler {
+            request: Arc::new(Mutex::new(Some(request))),
+            reply: Arc::new(Mutex::new(Some(reply))),
+        }
+    }
+
+    /// A handler with `message` already waiting as its request, and the receiver for its reply
+    pub fn request(message: Message) -> (Self, oneshot::Receiver<Message>) {
+        let (request_sender, request_receiver) = oneshot::channel();
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        // The receiver is right here, so this cannot fail
+        let _ = request_sender.send(message);
+        (OneshotHandler::new(request_receiver, reply_sender), reply_receiver)
+    }
+}
+
+impl<Message> Clone for OneshotHandler<Message> {
+    fn clone(&self) -> Self {
+        OneshotHandler { request: self.request.clone(), reply: self.reply.clone() }
+    }
+}
+
+impl<Message> ServiceHandler<Message> for OneshotHandler<Message>
+where
+    Message: Send,
+{
+    type Error = StandardHandlerError;
+
+    async fn send_message(&mut self, msg: Message) -> Result<(), StandardHandlerError> {
+        let reply = self.reply.lock().await.take().ok_or(StandardHandlerError::Closed)?;
+        reply.send(msg).map_err(|_| StandardHandlerError::Closed)
+    }
+
+    async fn receive_message(&mut self) -> Result<Option<Message>, StandardHandlerError> {
+        let Some(request) = self.request.lock().await.take() else {
+            return Ok(None);
+        };
+        // A request sender that was dropped without sending means there is no request
+        Ok(request.await.ok())
+    }
+
+    /// Drops the reply sender without replying
+    async fn close(&mut self) -> Result<(), StandardHandlerError> {
+        self.reply.lock().await.take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::framing::NewlineDelimited;
+    use crate::handlers::{BroadcastHandler, ChannelHandler, OneshotHandler, StandardHandlerError, StreamHandler};
+    use crate::pattern_bridge::Utf8;
+    use crate::pattern_handler::{HandlerPatternLayer, ServiceHandler};
+    use crate::pattern_injected::InjectedPatternLayer;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::{broadcast, mpsc};
+    use tower::{service_fn, Layer, Service};
+
+    #[tokio::test]
+    async fn test_channel_handler_with_injected_layer() {
+        // The peer on the other end of the channels asks for a doubling, and gets the result back
+        let (to_handler, handler_receiver) = mpsc::channel(1);
+        let (handler_sender, mut from_handler) = mpsc::channel(1);
+        let handler = ChannelHandler::new(handler_sender, handler_receiver);
+        let mut service = InjectedPatternLayer::new(handler.clone()).layer(service_fn(|value: u32| async move { Ok::<_, ()>(value * 2) }));
+
+        let peer = tokio::spawn(async move {
+            let first = from_handler.recv().await.unwrap();
+            to_handler.send(first + 1).await.unwrap();
+            from_handler.recv().await.unwrap()
+        });
+        service.call(10).await.unwrap();
+        assert_eq!(peer.await.unwrap(), 22);
+
+        // Once closed, every clone is closed
+        handler.clone().close().await.unwrap();
+        assert!(matches!(handler.clone().send_message(1).await, Err(StandardHandlerError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_channel_handler_close_while_send_is_blocked() {
+        let (handler_sender, mut peer) = mpsc::channel(1);
+        let (_to_handler, handler_receiver) = mpsc::channel::<u32>(1);
+        let handler = ChannelHandler::new(handler_sender, handler_receiver);
+        handler.clone().send_message(1).await.unwrap();
+        // The channel is full, so this send waits for the peer, which is not reading
+        let blocked = tokio::spawn({
+            let mut handler = handler.clone();
+            async move { handler.send_message(2).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!blocked.is_finished());
+
+        // Closing does not wait for the blocked send, and the blocked send gives up
+        tokio::time::timeout(Duration::from_secs(1), handler.clone().close()).await.unwrap().unwrap();
+        assert!(matches!(blocked.await.unwrap(), Err(StandardHandlerError::Closed)));
+        assert_eq!(peer.recv().await, Some(1));
+        assert_eq!(peer.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler_with_injected_layer() {
+        let (handler_side, mut peer_side) = duplex(1024);
+        let mut handler = StreamHandler::new(handler_side, NewlineDelimited::new(1024), Utf8);
+        let mut service = InjectedPatternLayer::new(handler.clone()).layer(service_fn(|name: String| async move { Ok::<_, ()>(format!("Hello, {name}!!!")) }));
+
+        let peer = tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let sz = peer_side.read(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..sz], b"hello?\n");
+            // The reply arrives in two pieces, but is still one message
+            peer_side.write_all(b"Rapid").await.unwrap();
+            peer_side.write_all(b"Recast\n").await.unwrap();
+            let mut rest = Vec::new();
+            peer_side.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+        service.call("hello?".to_string()).await.unwrap();
+        ServiceHandler::<String>::close(&mut handler).await.unwrap();
+        assert_eq!(peer.await.unwrap(), b"Hello, RapidRecast!!!\n");
+        // The peer is gone, so there is nothing more to receive
+        assert_eq!(ServiceHandler::<String>::receive_message(&mut handler).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_handler_fans_out() {
+        let (outgoing, _) = broadcast::channel::<u32>(4);
+        let (incoming, _) = broadcast::channel::<u32>(4);
+        let mut subscriber = incoming.subscribe();
+        let handler = BroadcastHandler::new(incoming.clone(), outgoing.subscribe());
+        let mut service = HandlerPatternLayer::new().layer(service_fn(move |_: ()| {
+            let handler = handler.clone();
+            async move { Ok::<_, ()>(handler) }
+        }));
+
+        // Both replies are published before the exchange starts
+        outgoing.send(1).unwrap();
+        outgoing.send(2).unwrap();
+        let (caller, reply) = OneshotHandler::request(100);
+        service.call(caller).await.unwrap();
+        assert_eq!(reply.await.unwrap(), 3);
+        // The request went out to every subscriber
+        assert_eq!(subscriber.recv().await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_oneshot_handler_with_handler_layer() {
+        let (to_inner, inner_receiver) = mpsc::channel(2);
+        let (inner_sender, mut from_inner) = mpsc::channel(2);
+        let inner = ChannelHandler::new(inner_sender, inner_receiver);
+        let mut service = HandlerPatternLayer::new().layer(service_fn(move |_: ()| {
+            let inner = inner.clone();
+            async move { Ok::<_, ()>(inner) }
+        }));
+        to_inner.send(4).await.unwrap();
+        to_inner.send(5).await.unwrap();
+
+        let (mut caller, reply) = OneshotHandler::request(7);
+        service.call(caller.clone()).await.unwrap();
+        assert_eq!(reply.await.unwrap(), 9);
+        assert_eq!(from_inner.recv().await.unwrap(), 7);
+        // The default mediator closes the inner handler once it is done
+        assert_eq!(from_inner.recv().await, None);
+        // There is only ever one request and one reply
+        assert_eq!(caller.receive_message().await.unwrap(), None);
+        assert!(matches!(caller.send_message(1).await, Err(StandardHandlerError::Closed)));
+    }
+}
@@ -1,19 +1,20 @@
 use crate::pattern_handler::ServiceHandler;
+use std::convert::Infallible;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 pub struct DataTypeA(pub u8);
 pub struct DataTypeB(pub u32);
 
-impl Into<DataTypeB> for DataTypeA {
-    fn into(self) -> DataTypeB {
-        DataTypeB(self.0 as u32)
+impl From<DataTypeA> for DataTypeB {
+    fn from(value: DataTypeA) -> Self {
+        DataTypeB(value.0 as u32)
     }
 }
 
-impl Into<DataTypeA> for DataTypeB {
-    fn into(self) -> DataTypeA {
-        DataTypeA(self.0 as u8)
+impl From<DataTypeB> for DataTypeA {
+    fn from(value: DataTypeB) -> Self {
+        DataTypeA(value.0 as u8)
     }
 }
 
@@ -34,13 +35,22 @@ impl IncrementingHandler {
     }
 }
 
+/// The value is always there, so this handler can neither fail nor run out of messages
 impl ServiceHandler<u32> for IncrementingHandler {
-    async fn send_message(&mut self, msg: u32) -> () {
+    type Error = Infallible;
+
+    async fn send_message(&mut self, msg: u32) -> Result<(), Infallible> {
         self.value.store(msg, Ordering::SeqCst);
+        Ok(())
     }
 
     /// Every time a message is requested, we increment the stored value
-    async fn receive_message(&mut self) -> u32 {
-        self.value.fetch_add(1, Ordering::SeqCst)
+    async fn receive_message(&mut self) -> Result<Option<u32>, Infallible> {
+        Ok(Some(self.value.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    /// There is nobody on the other side to tell
+    async fn close(&mut self) -> Result<(), Infallible> {
+        Ok(())
     }
 }
\ No newline at end of file
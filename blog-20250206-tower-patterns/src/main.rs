@@ -1,10 +1,18 @@
+use crate::error::LayerError;
+use crate::framing::{FixedSize, FrameCodec, FrameError, LengthDelimited, NewlineDelimited};
+use crate::handlers::{BroadcastHandler, ChannelHandler, OneshotHandler, StreamHandler};
 use crate::helper::{DataTypeA, DataTypeB, IncrementingHandler};
-use crate::pattern_basic::BasicPatternLayer;
-use crate::pattern_chan::ChannelPatternLayer;
-use crate::pattern_handler::{HandlerPatternLayer, ServiceHandler};
-use crate::pattern_injected::InjectedPatternLayer;
+use crate::pattern_basic::{BasicPatternLayer, TryBasicPatternLayer};
+use crate::pattern_bridge::{ChannelToIoLayer, IoToChannelLayer, Utf8};
+use crate::pattern_chan::{ChannelCapacity, ChannelPatternLayer};
+use crate::pattern_handler::{expect_message, HandlerError, HandlerPatternLayer, ServiceHandler};
+use crate::pattern_injected::{InjectedPatternLayer, Route};
 use crate::pattern_io::IoPatternLayer;
-use tokio::io::{simplex, AsyncReadExt, AsyncWriteExt};
+use crate::transform::{ByteTransform, Identity, Reverse, RunningChecksum, XorMask};
+use std::error::Error;
+use std::time::Duration;
+use tokio::io::{duplex, simplex, split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, watch};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tower::{service_fn, Service, ServiceBuilder};
 
@@ -15,14 +23,26 @@ mod pattern_handler;
 mod error;
 mod helper;
 mod pattern_injected;
+mod transform;
+mod framing;
+mod pattern_bridge;
+mod session;
+mod handlers;
 
 #[tokio::main]
 async fn main() {
     basic_example().await;
     io_example().await;
+    io_transform_example().await;
+    io_framing_example().await;
     channel_example().await;
+    channel_capacity_example().await;
+    bridge_example().await;
     handler_example().await;
     injected_example().await;
+    standard_handlers_example().await;
+    upgrade_example().await;
+    error_example().await;
 }
 
 async fn basic_example() {
@@ -34,6 +54,15 @@ async fn basic_example() {
     let input: u8 = 5;
     let output: u32 = basic_service.call(input).await.unwrap();
     assert_eq!(output, 5);
+
+    // When not every input can be represented downstream, the conversion is tried instead, and a
+    // failure is reported rather than silently truncated. The response can be converted back too.
+    let mut try_service = ServiceBuilder::new()
+        .layer(TryBasicPatternLayer::<u32, u16, u32>::new().with_response_map(DataTypeB))
+        .service(service_fn(basic_service_fn));
+    assert_eq!(try_service.call(5).await.unwrap().0, 5);
+    let result = try_service.call(70_000).await;
+    assert!(matches!(result, Err(LayerError::Conversion(_))));
     println!("Basic pattern test passed");
 }
 
@@ -62,6 +91,86 @@ async fn io_example() {
     println!("I/O pattern test passed");
 }
 
+async fn io_transform_example() {
+    // The transforms are pluggable, and each direction gets its own.
+    // Here the caller speaks an XOR-masked protocol, and the inner service speaks plain text.
+    // Another layer below only observes the traffic, keeping a checksum of what reached the service.
+    let (report, checksum) = watch::channel(0);
+    let mut io_service = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(XorMask::new(*b"secret"), XorMask::new(*b"secret")))
+        .layer(IoPatternLayer::new(RunningChecksum::reporting(move |sum| { report.send_replace(sum); }), Identity))
+        .service(service_fn(io_service_fn));
+    let (tower_read, mut this_write) = simplex(1024);
+    let (mut this_read, tower_write) = simplex(1024);
+    let task = tokio::spawn(io_service.call((tower_read, tower_write)));
+
+    // We mask what we send and unmask what we receive, just like a client of this protocol would
+    this_write.write_all(&XorMask::new(*b"secret").transform(b"RapidRecast".to_vec())).await.unwrap();
+    let mut buffer = [0u8; 1024];
+    let sz = this_read.read(&mut buffer).await.unwrap();
+    let response = XorMask::new(*b"secret").transform(buffer[..sz].to_vec());
+    assert_eq!(response, b"Hello, RapidRecast!!!");
+    drop(this_write);
+    drop(this_read);
+    task.await.unwrap().unwrap();
+
+    let mut expected = RunningChecksum::default();
+    expected.transform(b"RapidRecast".to_vec());
+    assert_eq!(*checksum.borrow(), expected.checksum());
+    println!("I/O transform pattern test passed");
+}
+
+async fn io_framing_example() {
+    // With framing, the layer relays whole messages instead of whatever a read happened to return.
+    // Here the caller speaks length-delimited frames, and the inner service speaks lines.
+    let mut io_service = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(Identity, Identity).with_framing(LengthDelimited::new(1024), NewlineDelimited::new(1024)))
+        .service(service_fn(line_service_fn));
+    // A duplex pipe, unlike a simplex one, lets us signal that we are done sending
+    let (this_side, tower_side) = duplex(1024);
+    let (mut this_read, mut this_write) = split(this_side);
+    let task = tokio::spawn(io_service.call(split(tower_side)));
+
+    // Both messages go out in a single write, and still arrive as two lines
+    let mut codec = LengthDelimited::new(1024);
+    let mut bytes = codec.encode(b"Rapid".to_vec()).unwrap();
+    bytes.extend(codec.encode(b"Recast".to_vec()).unwrap());
+    this_write.write_all(&bytes).await.unwrap();
+    this_write.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    this_read.read_to_end(&mut response).await.unwrap();
+    assert_eq!(codec.decode(&mut response).unwrap().unwrap(), b"Hello, Rapid!!!");
+    assert_eq!(codec.decode(&mut response).unwrap().unwrap(), b"Hello, Recast!!!");
+    task.await.unwrap().unwrap();
+
+    // Fixed-size records are reversed one record at a time, however they are split when written
+    let mut io_service = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(Reverse, Identity).with_framing(FixedSize::new(4), FixedSize::new(4)))
+        .service(service_fn(echo_service_fn));
+    let (this_side, tower_side) = duplex(1024);
+    let (mut this_read, mut this_write) = split(this_side);
+    let task = tokio::spawn(io_service.call(split(tower_side)));
+    this_write.write_all(b"abcde").await.unwrap();
+    this_write.write_all(b"fgh").await.unwrap();
+    this_write.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    this_read.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"dcbahgfe");
+    task.await.unwrap().unwrap();
+
+    // A frame bigger than allowed ends the session with a typed error
+    let mut io_service = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(Identity, Identity).with_framing(NewlineDelimited::new(8), NewlineDelimited::new(8)))
+        .service(service_fn(line_service_fn));
+    let (this_side, tower_side) = duplex(1024);
+    let (_this_read, mut this_write) = split(this_side);
+    let task = tokio::spawn(io_service.call(split(tower_side)));
+    this_write.write_all(b"RapidRecast\n").await.unwrap();
+    let result = task.await.unwrap();
+    assert!(matches!(result, Err(LayerError::Framing(FrameError::TooLarge { size: 11, max: 8 }))));
+    println!("I/O framing pattern test passed");
+}
+
 async fn channel_example() {
     // The channel pattern behaves similarly to the IO pattern, but it sends types between the layers
     // instead of bytes. This would tend to be the interface that users of the layer would be
@@ -72,7 +181,7 @@ async fn channel_example() {
 
     // We will create the channel pair that we will send to the service.
     let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
-    let (mut sender, svc_receiver) = channel::<DataTypeA>(1);
+    let (sender, svc_receiver) = channel::<DataTypeA>(1);
     let task = tokio::spawn(chan_service.call((svc_receiver, svc_sender)));
 
     // We send an input
@@ -87,6 +196,72 @@ async fn channel_example() {
     println!("Channel pattern test passed");
 }
 
+async fn channel_capacity_example() {
+    // The capacity decides what happens when the inner service falls behind: the caller waits,
+    // everything is queued, or the oldest messages are dropped. The metrics show which one happened.
+    for capacity in [ChannelCapacity::Bounded(1), ChannelCapacity::Unbounded, ChannelCapacity::DropOldest(2)] {
+        let layer = ChannelPatternLayer::with_capacity(capacity);
+        let metrics = layer.metrics();
+        let mut chan_service = ServiceBuilder::new()
+            .layer(layer)
+            .service(service_fn(slow_chan_service_fn));
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(chan_service.call((svc_receiver, svc_sender)));
+        // We send from another task, because with a bounded capacity the replies have to be read
+        // while we are still sending, or both directions would wait on each other forever
+        let sending = tokio::spawn(async move {
+            for value in 0..10 {
+                sender.send(DataTypeA(value)).await.unwrap();
+            }
+        });
+        while receiver.recv().await.is_some() {}
+        sending.await.unwrap();
+        task.await.unwrap().unwrap();
+
+        let upstream = &metrics.upstream;
+        assert_eq!(upstream.received(), 10);
+        assert_eq!(upstream.sent() + upstream.dropped(), 10);
+        assert_eq!(upstream.queue_depth(), 0);
+        println!(
+            "{capacity:?}: {} sent, {} dropped, queue depth up to {}, blocked for {:?}",
+            upstream.sent(),
+            upstream.dropped(),
+            upstream.max_queue_depth(),
+            upstream.blocked(),
+        );
+    }
+    println!("Channel capacity pattern test passed");
+}
+
+async fn bridge_example() {
+    // The bridge layers connect the IO pattern to the channel pattern, so a whole server can be
+    // composed from the bytes on the socket all the way down to a handler of typed messages.
+    // The server receives XOR-masked lines, and its handler only ever sees strings.
+    // Sessions are given a deadline, so a peer that stops talking cannot hold on to one forever.
+    let deadline = Duration::from_secs(5);
+    let server = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(XorMask::new(*b"secret"), XorMask::new(*b"secret")).with_timeout(deadline))
+        .layer(IoToChannelLayer::new(Utf8, NewlineDelimited::new(1024)).with_timeout(deadline))
+        .layer(ChannelPatternLayer::<String>::new())
+        .service(service_fn(greeting_service_fn));
+
+    // The client is the same stack upside down: typed messages go in, masked lines go out
+    let mut client = ServiceBuilder::new()
+        .layer(ChannelToIoLayer::new(Utf8, NewlineDelimited::new(1024)).with_timeout(deadline))
+        .layer(IoPatternLayer::new(XorMask::new(*b"secret"), XorMask::new(*b"secret")))
+        .service(server);
+
+    let (sender, svc_receiver) = channel::<String>(1);
+    let (svc_sender, mut receiver) = channel::<String>(1);
+    let task = tokio::spawn(client.call((svc_receiver, svc_sender)));
+    sender.send("RapidRecast".to_string()).await.unwrap();
+    assert_eq!(receiver.recv().await.unwrap(), "Hello, RapidRecast!!!");
+    drop(sender);
+    task.await.unwrap().unwrap();
+    println!("Bridge pattern test passed");
+}
+
 async fn handler_example() {
     let mut handler_service = ServiceBuilder::new()
         .layer(HandlerPatternLayer::new())
@@ -95,7 +270,40 @@ async fn handler_example() {
     let mut our_handler = IncrementingHandler::new(654);
     handler_service.call(our_handler.clone()).await.unwrap();
 
-    assert_eq!(our_handler.receive_message().await, 654 + 655);
+    assert_eq!(our_handler.receive_message().await.unwrap(), Some(654 + 655));
+
+    // The exchange itself is pluggable. This mediator keeps asking the inner handler until the
+    // value passes whatever the caller asked for, and reports how many round trips it took
+    let mediator = |mut caller: IncrementingHandler, mut inner: IncrementingHandler| async move {
+        let target = expect_message(&mut caller).await?;
+        let mut round_trips = 0;
+        while expect_message(&mut inner).await? < target {
+            round_trips += 1;
+        }
+        caller.send_message(round_trips).await.map_err(HandlerError::failed)
+    };
+    let mut handler_service = ServiceBuilder::new()
+        .layer(HandlerPatternLayer::with_mediator(mediator))
+        .service(service_fn(handler_service_fn));
+    let mut our_handler = IncrementingHandler::new(130);
+    handler_service.call(our_handler.clone()).await.unwrap();
+    // The inner handler starts at 123, so it takes 7 round trips to reach 130
+    assert_eq!(our_handler.receive_message().await.unwrap(), Some(7));
+
+    // A mediator can also end the exchange early with an error, which the layer reports
+    let mediator = |mut caller: IncrementingHandler, mut inner: IncrementingHandler| async move {
+        let target = expect_message(&mut caller).await?;
+        if expect_message(&mut inner).await? > target {
+            return Err(HandlerError::failed(std::io::Error::other("the inner handler is already past the target")));
+        }
+        Ok(())
+    };
+    let mut handler_service = ServiceBuilder::new()
+        .layer(HandlerPatternLayer::with_mediator(mediator))
+        .service(service_fn(handler_service_fn));
+    let result = handler_service.call(IncrementingHandler::new(100)).await;
+    let Err(LayerError::Handler(HandlerError::Failed(e))) = result else { panic!("the exchange should have failed") };
+    assert_eq!(e.to_string(), "the inner handler is already past the target");
     println!("Handler pattern test passed");
 }
 
@@ -112,11 +320,134 @@ async fn injected_example() {
     // internal handler
     injected_service.call(200).await.unwrap();
 
-    let final_result = internal_handler.receive_message().await;
-    assert_eq!(final_result, 200 * 2);
+    let final_result = internal_handler.receive_message().await.unwrap();
+    assert_eq!(final_result, Some(200 * 2));
     println!("Injected pattern test passed");
 }
 
+async fn standard_handlers_example() {
+    // A caller with a single request and a single reply, talking to an inner service whose
+    // handler is a plain mpsc pair
+    let (to_inner, inner_receiver) = channel::<u32>(2);
+    let (inner_sender, mut from_inner) = channel::<u32>(2);
+    let inner_handler = ChannelHandler::new(inner_sender, inner_receiver);
+    let mut handler_service = ServiceBuilder::new()
+        .layer(HandlerPatternLayer::new())
+        .service(service_fn(move |_: ()| {
+            let inner_handler = inner_handler.clone();
+            async move { Ok::<_, ()>(inner_handler) }
+        }));
+    to_inner.send(20).await.unwrap();
+    to_inner.send(22).await.unwrap();
+    let (caller, reply) = OneshotHandler::request(1);
+    handler_service.call(caller).await.unwrap();
+    assert_eq!(from_inner.recv().await, Some(1));
+    assert_eq!(reply.await.unwrap(), 42);
+
+    // The injected handler speaks lines of text over a socket-like stream
+    let (handler_side, mut peer_side) = duplex(1024);
+    let mut injected_service = ServiceBuilder::new()
+        .layer(InjectedPatternLayer::new(StreamHandler::new(handler_side, NewlineDelimited::new(1024), Utf8)))
+        .service(service_fn(|name: String| async move { Ok::<_, ()>(format!("Hello, {name}!!!")) }));
+    let peer = tokio::spawn(async move {
+        let mut buffer = [0u8; 1024];
+        let sz = peer_side.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..sz], b"who is there?\n");
+        peer_side.write_all(b"RapidRecast\n").await.unwrap();
+        let sz = peer_side.read(&mut buffer).await.unwrap();
+        String::from_utf8(buffer[..sz].to_vec()).unwrap()
+    });
+    injected_service.call("who is there?".to_string()).await.unwrap();
+    assert_eq!(peer.await.unwrap(), "Hello, RapidRecast!!!\n");
+
+    // The injected handler gets its messages from a broadcast, and publishes to every subscriber
+    let (announcements, _) = broadcast::channel::<u32>(4);
+    let (results, _) = broadcast::channel::<u32>(4);
+    let mut first_subscriber = results.subscribe();
+    let mut second_subscriber = results.subscribe();
+    let mut injected_service = ServiceBuilder::new()
+        .layer(InjectedPatternLayer::new(BroadcastHandler::new(results.clone(), announcements.subscribe())))
+        .service(service_fn(handle_injected_fn));
+    announcements.send(50).unwrap();
+    injected_service.call(7).await.unwrap();
+    // Both subscribers see the request, then the announcement doubled by the inner service
+    for subscriber in [&mut first_subscriber, &mut second_subscriber] {
+        assert_eq!(subscriber.recv().await.unwrap(), 7);
+        assert_eq!(subscriber.recv().await.unwrap(), 100);
+    }
+    println!("Standard handlers test passed");
+}
+
+async fn upgrade_example() {
+    // A connection starts out in request/response mode, where every request is answered by the
+    // inner tower service and the handler writes the response to the connection.
+    // Once the client asks for an upgrade, the session switches to streaming mode for good, and
+    // everything goes to the handler, which streams it straight to the connection.
+    let (handler_side, mut client_side) = duplex(1024);
+    let connection = StreamHandler::new(handler_side, NewlineDelimited::new(1024), Utf8);
+    let layer = InjectedPatternLayer::new(connection).with_classifier(|line: &String| match line.as_str() {
+        "UPGRADE stream" => Route::Upgrade,
+        _ => Route::Inner,
+    });
+    let mut session = ServiceBuilder::new()
+        .layer(layer)
+        .service(service_fn(request_service_fn));
+
+    for line in ["GET /time", "GET /missing", "UPGRADE stream", "GET /time", "tick 1", "tick 2"] {
+        session.call(line.to_string()).await.unwrap();
+    }
+    assert!(session.is_upgraded());
+    drop(session);
+
+    let mut lines = BufReader::new(&mut client_side).lines();
+    let mut received = Vec::new();
+    for _ in 0..6 {
+        received.push(lines.next_line().await.unwrap().unwrap());
+    }
+    // After the upgrade, even what looks like a request is just part of the stream
+    assert_eq!(received, ["200 12:00", "404 GET /missing", "UPGRADE stream", "GET /time", "tick 1", "tick 2"]);
+    println!("Upgrade pattern test passed");
+}
+
+async fn error_example() {
+    // Errors from a layer say whether the layer or the inner service failed, and keep the cause
+    let Err(report) = failing_io_fn().await else { panic!("the inner service should have failed") };
+    assert_eq!(report.to_string(), "inner service failed");
+    assert_eq!(report.source().unwrap().to_string(), "backend unavailable");
+
+    // A channel session that takes longer than the layer allows is stopped, along with its inner service
+    let timeout = Duration::from_millis(1);
+    let mut slow_service = ServiceBuilder::new()
+        .layer(ChannelPatternLayer::new().with_timeout(timeout))
+        .service(service_fn(slow_chan_service_fn));
+    let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+    let (sender, svc_receiver) = channel::<DataTypeA>(1);
+    sender.send(DataTypeA(5)).await.unwrap();
+    let result = slow_service.call((svc_receiver, svc_sender)).await;
+    assert!(matches!(result, Err(LayerError::Timeout(d)) if d == timeout));
+    assert!(receiver.recv().await.is_none());
+    println!("Layer error test passed");
+}
+
+async fn failing_io_fn() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut service = ServiceBuilder::new()
+        .layer(IoPatternLayer::new(Identity, Identity))
+        .service(service_fn(|_: (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)| async {
+            Err::<(), _>(std::io::Error::other("backend unavailable"))
+        }));
+    let (_caller, layer) = duplex(1024);
+    // LayerError is a std::error::Error, so `?` works as it does with any other error
+    service.call(split(layer)).await?;
+    Ok(())
+}
+
+async fn request_service_fn(request: String) -> Result<String, ()> {
+    Ok(match request.as_str() {
+        "GET /time" => "200 12:00".to_string(),
+        _ => format!("404 {request}"),
+    })
+}
+
 async fn basic_service_fn(input: u16) -> Result<u32, ()> {
     Ok(input as u32)
 }
@@ -135,6 +466,20 @@ async fn io_service_fn<Reader: AsyncReadExt + Unpin, Writer: AsyncWriteExt + Unp
     Ok(())
 }
 
+async fn line_service_fn<Reader: AsyncReadExt + Unpin, Writer: AsyncWriteExt + Unpin>((reader, mut writer): (Reader, Writer)) -> Result<(), ()> {
+    // Greets every line, until the caller is done
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.map_err(|_| ())? {
+        writer.write_all(format!("Hello, {line}!!!\n").as_bytes()).await.map_err(|_| ())?;
+    }
+    writer.shutdown().await.map_err(|_| ())
+}
+
+async fn echo_service_fn<Reader: AsyncReadExt + Unpin, Writer: AsyncWriteExt + Unpin>((mut reader, mut writer): (Reader, Writer)) -> Result<(), ()> {
+    tokio::io::copy(&mut reader, &mut writer).await.map_err(|_| ())?;
+    writer.shutdown().await.map_err(|_| ())
+}
+
 async fn chan_service_fn((mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)) -> Result<(), ()> {
     let value = receiver.recv().await.unwrap();
     // Double the value and make sure it fits in a u8
@@ -142,6 +487,20 @@ async fn chan_service_fn((mut receiver, sender): (Receiver<DataTypeB>, Sender<Da
     Ok(())
 }
 
+async fn slow_chan_service_fn((mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)) -> Result<(), ()> {
+    // Takes a while to get going, so the messages pile up in the layer
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    while let Some(value) = receiver.recv().await {
+        sender.send(value).await.map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+async fn greeting_service_fn((mut receiver, sender): (Receiver<String>, Sender<String>)) -> Result<(), ()> {
+    let name = receiver.recv().await.ok_or(())?;
+    sender.send(format!("Hello, {name}!!!")).await.map_err(|_| ())
+}
+
 async fn handler_service_fn(_tower_input: ()) -> Result<IncrementingHandler, ()> {
     // We set the default value to 123, but we don't actually expect it to be used
     // It will be overwritten by the layer as a first step
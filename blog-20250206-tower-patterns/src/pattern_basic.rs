@@ -1,3 +1,4 @@
+use crate::error::LayerError;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
@@ -76,6 +77,162 @@ where
     type Service = BasicPatternService<InnerService, InputType, OutputType, ReturnType>;
 
     fn layer(&self, inner: InnerService) -> Self::Service {
-        BasicPatternService { inner, _phantom_input: PhantomData::default(), _phantom_output: PhantomData::default(), _phantom_return: PhantomData::default() }
+        BasicPatternService { inner, _phantom_input: PhantomData, _phantom_output: PhantomData, _phantom_return: PhantomData }
+    }
+}
+
+/// Turns what the inner service returns into what the caller expects
+pub trait ResponseMap<ReturnType, CallerReturn>: Clone + Send + 'static {
+    fn map(&mut self, response: ReturnType) -> CallerReturn;
+}
+
+/// A function or closure does the mapping, for example the constructor of a tuple struct
+impl<F, ReturnType, CallerReturn> ResponseMap<ReturnType, CallerReturn> for F
+where
+    F: FnMut(ReturnType) -> CallerReturn + Clone + Send + 'static,
+{
+    fn map(&mut self, response: ReturnType) -> CallerReturn {
+        self(response)
+    }
+}
+
+/// Passes responses back to the caller as they are
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Unchanged;
+
+impl<ReturnType> ResponseMap<ReturnType, ReturnType> for Unchanged {
+    fn map(&mut self, response: ReturnType) -> ReturnType {
+        response
+    }
+}
+
+/// Like the Basic Pattern Service, but the request conversion may fail, and the response can be
+/// converted on the way back, so that both sides of a protocol boundary are adapted in one place
+pub struct TryBasicPatternService<InnerService, InputType, OutputType, ReturnType, CallerReturn, Map> {
+    inner: InnerService,
+    map: Map,
+    _phantom_input: PhantomData<InputType>,
+    _phantom_output: PhantomData<OutputType>,
+    _phantom_return: PhantomData<ReturnType>,
+    _phantom_caller_return: PhantomData<CallerReturn>,
+}
+
+impl<InnerService, InputType, OutputType, ReturnType, CallerReturn, Map> Service<InputType> for TryBasicPatternService<InnerService, InputType, OutputType, ReturnType, CallerReturn, Map>
+where
+    InnerService: Service<OutputType, Response=ReturnType> + Clone + Send + 'static,
+    InnerService::Error: Send + 'static,
+    InnerService::Future: Future<Output=Result<ReturnType, InnerService::Error>> + Send + 'static,
+    InputType: TryInto<OutputType> + Send + 'static,
+// The conversion error is boxed, so that the layer error does not need another type parameter
+    InputType::Error: std::error::Error + Send + Sync + 'static,
+    OutputType: Send + 'static,
+    ReturnType: Send + 'static,
+    CallerReturn: Send + 'static,
+    Map: ResponseMap<ReturnType, CallerReturn>,
+{
+    type Response = CallerReturn;
+    type Error = LayerError<InnerService::Error>;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
+    }
+
+    fn call(&mut self, input: InputType) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let mut map = self.map.clone();
+        Box::pin(async move {
+            // A request that cannot be converted never reaches the inner service
+            let request = input.try_into().map_err(|e| LayerError::Conversion(Box::new(e)))?;
+            let response = inner.call(request).await.map_err(LayerError::InnerError)?;
+            Ok(map.map(response))
+        })
+    }
+}
+
+pub struct TryBasicPatternLayer<InputType, OutputType, ReturnType, CallerReturn = ReturnType, Map = Unchanged> {
+    map: Map,
+    _phantom_input: PhantomData<InputType>,
+    _phantom_output: PhantomData<OutputType>,
+    _phantom_return: PhantomData<ReturnType>,
+    _phantom_caller_return: PhantomData<CallerReturn>,
+}
+
+impl<InputType, OutputType, ReturnType> TryBasicPatternLayer<InputType, OutputType, ReturnType>
+where
+    InputType: TryInto<OutputType> + Send + 'static,
+    OutputType: Send + 'static,
+    ReturnType: Send + 'static,
+{
+    /// Converts requests with [TryInto], and passes responses back [Unchanged]
+    pub fn new() -> Self {
+        TryBasicPatternLayer {
+            map: Unchanged,
+            _phantom_input: PhantomData,
+            _phantom_output: PhantomData,
+            _phantom_return: PhantomData,
+            _phantom_caller_return: PhantomData,
+        }
+    }
+
+    /// Also converts every response with `map` before it goes back to the caller
+    pub fn with_response_map<CallerReturn, Map>(self, map: Map) -> TryBasicPatternLayer<InputType, OutputType, ReturnType, CallerReturn, Map>
+    where
+        Map: ResponseMap<ReturnType, CallerReturn>,
+    {
+        TryBasicPatternLayer {
+            map,
+            _phantom_input: PhantomData,
+            _phantom_output: PhantomData,
+            _phantom_return: PhantomData,
+            _phantom_caller_return: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, InputType, OutputType, ReturnType, CallerReturn, Map> Layer<InnerService> for TryBasicPatternLayer<InputType, OutputType, ReturnType, CallerReturn, Map>
+where
+    InnerService: Service<OutputType, Response=ReturnType> + Clone + Send + 'static,
+    InputType: TryInto<OutputType> + Send + 'static,
+    OutputType: Send + 'static,
+    ReturnType: Send + 'static,
+    CallerReturn: Send + 'static,
+    Map: ResponseMap<ReturnType, CallerReturn>,
+{
+    type Service = TryBasicPatternService<InnerService, InputType, OutputType, ReturnType, CallerReturn, Map>;
+
+    fn layer(&self, inner: InnerService) -> Self::Service {
+        TryBasicPatternService {
+            inner,
+            map: self.map.clone(),
+            _phantom_input: PhantomData,
+            _phantom_output: PhantomData,
+            _phantom_return: PhantomData,
+            _phantom_caller_return: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::pattern_basic::TryBasicPatternLayer;
+    use std::num::TryFromIntError;
+    use tower::{service_fn, Layer, Service};
+
+    #[tokio::test]
+    async fn test_fallible_conversion_and_response_map() {
+        let double = service_fn(|request: u8| async move { request.checked_mul(2).ok_or("overflow") });
+        let mut service = TryBasicPatternLayer::<u32, u8, u8>::new()
+            .with_response_map(|response: u8| format!("{response:#04x}"))
+            .layer(double);
+        assert_eq!(service.call(100).await.unwrap(), "0xc8");
+
+        // 300 does not fit in a u8, so the inner service is never called
+        let Err(LayerError::Conversion(e)) = service.call(300).await else { panic!("expected a conversion error") };
+        assert!(e.is::<TryFromIntError>());
+
+        // Errors from the inner service are still passed on as they are
+        assert!(matches!(service.call(200).await, Err(LayerError::InnerError("overflow"))));
     }
 }
@@ -0,0 +1,431 @@
+use crate::error::LayerError;
+use crate::framing::{FrameCodec, FrameError};
+use crate::pattern_io::{write_frame, MAX_BUF_SIZE};
+use crate::session::run_session;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tower::{Layer, Service};
+
+/// How many decoded messages can wait for the other side before the bridge stops reading
+const BRIDGE_CHANNEL_SIZE: usize = 16;
+
+/// Turns a whole frame into a typed message
+pub trait MessageDecoder<Message>: Clone + Send + 'static {
+    fn decode(&mut self, frame: Vec<u8>) -> Result<Message, FrameError>;
+}
+
+/// Turns a typed message into a frame
+pub trait MessageEncoder<Message>: Clone + Send + 'static {
+    fn encode(&mut self, message: Message) -> Vec<u8>;
+}
+
+/// Every frame is a UTF-8 string
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Utf8;
+
+impl MessageDecoder<String> for Utf8 {
+    fn decode(&mut self, frame: Vec<u8>) -> Result<String, FrameError> {
+        String::from_utf8(frame).map_err(|_| FrameError::Malformed("frame is not valid UTF-8"))
+    }
+}
+
+impl MessageEncoder<String> for Utf8 {
+    fn encode(&mut self, message: String) -> Vec<u8> {
+        message.into_bytes()
+    }
+}
+
+/// The server side of the bridge: accepts a (read, write) pair, as the IO pattern does, and hands
+/// a typed (Receiver, Sender) pair to the inner service, as the channel pattern does.
+/// Inbound frames are decoded into requests, and the responses are encoded into outbound frames.
+pub struct IoToChannelService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(Receiver<Request>, Sender<Response>), Response=()>,
+{
+    inner: InnerService,
+    codec: Codec,
+    framing: Framing,
+    timeout: Option<Duration>,
+    _phantom_request: PhantomData<Request>,
+    _phantom_response: PhantomData<Response>,
+}
+
+impl<InnerService, Codec, Framing, Request, Response> Clone for IoToChannelService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(Receiver<Request>, Sender<Response>), Response=()> + Clone,
+    Codec: Clone,
+    Framing: Clone,
+{
+    fn clone(&self) -> Self {
+        IoToChannelService {
+            inner: self.inner.clone(),
+            codec: self.codec.clone(),
+            framing: self.framing.clone(),
+            timeout: self.timeout,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, Codec, Framing, Request, Response, Reader, Writer> Service<(Reader, Writer)> for IoToChannelService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(Receiver<Request>, Sender<Response>), Response=()> + Clone + Send + 'static,
+    InnerService::Future: Future<Output=Result<InnerService::Response, InnerService::Error>> + Send + 'static,
+    InnerService::Error: Send + 'static,
+    Codec: MessageDecoder<Request> + MessageEncoder<Response>,
+    Framing: FrameCodec,
+    Request: Send + 'static,
+    Response: Send + 'static,
+    Reader: AsyncRead + Send + Unpin + 'static,
+    Writer: AsyncWrite + Send + Unpin + 'static,
+{
+    type Response = ();
+    type Error = LayerError<InnerService::Error>;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
+    }
+
+    fn call(&mut self, (input_reader, input_writer): (Reader, Writer)) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let codec = self.codec.clone();
+        let framing = self.framing.clone();
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let (request_sender, request_receiver) = channel::<Request>(BRIDGE_CHANNEL_SIZE);
+            let (response_sender, response_receiver) = channel::<Response>(BRIDGE_CHANNEL_SIZE);
+            let task: JoinHandle<Result<InnerService::Response, InnerService::Error>> = tokio::spawn(inner.call((request_receiver, response_sender)));
+
+            let upstream = read_messages(input_reader, framing.clone(), codec.clone(), request_sender, "Failed to read from input reader");
+            let downstream = write_messages(response_receiver, input_writer, framing, codec, "Failed to write to input writer");
+            run_session(task, upstream, downstream, timeout).await
+        })
+    }
+}
+
+/// The layer for [IoToChannelService].
+/// `Codec` decodes requests and encodes responses, and `Framing` splits the bytes into frames.
+pub struct IoToChannelLayer<Codec, Framing, Request, Response> {
+    codec: Codec,
+    framing: Framing,
+    timeout: Option<Duration>,
+    _phantom_request: PhantomData<Request>,
+    _phantom_response: PhantomData<Response>,
+}
+
+impl<Codec, Framing, Request, Response> IoToChannelLayer<Codec, Framing, Request, Response>
+where
+    Codec: MessageDecoder<Request> + MessageEncoder<Response>,
+    Framing: FrameCodec,
+{
+    pub fn new(codec: Codec, framing: Framing) -> Self {
+        IoToChannelLayer {
+            codec,
+            framing,
+            timeout: None,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+
+    /// Limits how long each session may take. A session still going after `timeout` is stopped,
+    /// along with its inner service, and fails with [LayerError::Timeout].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<InnerService, Codec, Framing, Request, Response> Layer<InnerService> for IoToChannelLayer<Codec, Framing, Request, Response>
+where
+    InnerService: Service<(Receiver<Request>, Sender<Response>), Response=()> + Clone + Send + 'static,
+    Codec: MessageDecoder<Request> + MessageEncoder<Response>,
+    Framing: FrameCodec,
+{
+    type Service = IoToChannelService<InnerService, Codec, Framing, Request, Response>;
+
+    fn layer(&self, inner: InnerService) -> Self::Service {
+        IoToChannelService {
+            inner,
+            codec: self.codec.clone(),
+            framing: self.framing.clone(),
+            timeout: self.timeout,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+}
+
+/// The client side of the bridge, and the inverse of [IoToChannelService]: accepts a typed
+/// (Receiver, Sender) pair and hands a (read, write) pair to the inner service.
+/// The requests the caller sends are encoded into frames, and the frames that come back are decoded into responses.
+pub struct ChannelToIoService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()>,
+{
+    inner: InnerService,
+    codec: Codec,
+    framing: Framing,
+    timeout: Option<Duration>,
+    _phantom_request: PhantomData<Request>,
+    _phantom_response: PhantomData<Response>,
+}
+
+impl<InnerService, Codec, Framing, Request, Response> Clone for ChannelToIoService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()> + Clone,
+    Codec: Clone,
+    Framing: Clone,
+{
+    fn clone(&self) -> Self {
+        ChannelToIoService {
+            inner: self.inner.clone(),
+            codec: self.codec.clone(),
+            framing: self.framing.clone(),
+            timeout: self.timeout,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, Codec, Framing, Request, Response> Service<(Receiver<Request>, Sender<Response>)> for ChannelToIoService<InnerService, Codec, Framing, Request, Response>
+where
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()> + Clone + Send + 'static,
+    InnerService::Future: Future<Output=Result<InnerService::Response, InnerService::Error>> + Send + 'static,
+    InnerService::Error: Send + 'static,
+    Codec: MessageEncoder<Request> + MessageDecoder<Response>,
+    Framing: FrameCodec,
+    Request: Send + 'static,
+    Response: Send + 'static,
+{
+    type Response = ();
+    type Error = LayerError<InnerService::Error>;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
+    }
+
+    fn call(&mut self, (request_receiver, response_sender): (Receiver<Request>, Sender<Response>)) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let codec = self.codec.clone();
+        let framing = self.framing.clone();
+        let timeout = self.timeout;
+        Box::pin(async move {
+            // The same kind of pipe the IO pattern hands down, so the two can be stacked
+            let (this_side, svc_side) = duplex(MAX_BUF_SIZE);
+            let (read_this, write_this) = split(this_side);
+            let task: JoinHandle<Result<InnerService::Response, InnerService::Error>> = tokio::spawn(inner.call(split(svc_side)));
+
+            let upstream = write_messages(request_receiver, write_this, framing.clone(), codec.clone(), "Failed to write to inner service");
+            let downstream = read_messages(read_this, framing, codec, response_sender, "Failed to read from inner service");
+            run_session(task, upstream, downstream, timeout).await
+        })
+    }
+}
+
+/// The layer for [ChannelToIoService].
+/// `Codec` encodes requests and decodes responses, and `Framing` splits the bytes into frames.
+pub struct ChannelToIoLayer<Codec, Framing, Request, Response> {
+    codec: Codec,
+    framing: Framing,
+    timeout: Option<Duration>,
+    _phantom_request: PhantomData<Request>,
+    _phantom_response: PhantomData<Response>,
+}
+
+impl<Codec, Framing, Request, Response> ChannelToIoLayer<Codec, Framing, Request, Response>
+where
+    Codec: MessageEncoder<Request> + MessageDecoder<Response>,
+    Framing: FrameCodec,
+{
+    pub fn new(codec: Codec, framing: Framing) -> Self {
+        ChannelToIoLayer {
+            codec,
+            framing,
+            timeout: None,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+
+    /// Limits how long each session may take. A session still going after `timeout` is stopped,
+    /// along with its inner service, and fails with [LayerError::Timeout].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<InnerService, Codec, Framing, Request, Response> Layer<InnerService> for ChannelToIoLayer<Codec, Framing, Request, Response>
+where
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()> + Clone + Send + 'static,
+    Codec: MessageEncoder<Request> + MessageDecoder<Response>,
+    Framing: FrameCodec,
+{
+    type Service = ChannelToIoService<InnerService, Codec, Framing, Request, Response>;
+
+    fn layer(&self, inner: InnerService) -> Self::Service {
+        ChannelToIoService {
+            inner,
+            codec: self.codec.clone(),
+            framing: self.framing.clone(),
+            timeout: self.timeout,
+            _phantom_request: PhantomData,
+            _phantom_response: PhantomData,
+        }
+    }
+}
+
+/// Reads frames until EOF, decoding each one and sending it on.
+/// Dropping `sender` at the end is what tells the receiving side that no more messages are coming.
+async fn read_messages<Reader, Framing, Codec, Message, E>(mut reader: Reader, mut framing: Framing, mut codec: Codec, sender: Sender<Message>, read_error: &'static str) -> Result<(), LayerError<E>>
+where
+    Reader: AsyncRead + Unpin,
+    Framing: FrameCodec,
+    Codec: MessageDecoder<Message>,
+{
+    let mut pending = Vec::new();
+    let mut chunk = [0u8; MAX_BUF_SIZE];
+    loop {
+        let sz = reader.read(&mut chunk).await.map_err(|source| LayerError::Io { context: read_error, source })?;
+        if sz == 0 {
+            break;
+        }
+        pending.extend_from_slice(&chunk[..sz]);
+        while let Some(frame) = framing.decode(&mut pending).map_err(LayerError::Framing)? {
+            let message = codec.decode(frame).map_err(LayerError::Framing)?;
+            // If the receiver has gone there is nobody left to deliver to, which is not an error
+            if sender.send(message).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    if let Some(frame) = framing.decode_eof(&mut pending).map_err(LayerError::Framing)? {
+        let message = codec.decode(frame).map_err(LayerError::Framing)?;
+        let _ = sender.send(message).await;
+    }
+    Ok(())
+}
+
+/// Encodes and writes every message until all senders are gone, then shuts down `writer` to pass the closure on
+async fn write_messages<Writer, Framing, Codec, Message, E>(mut receiver: Receiver<Message>, mut writer: Writer, mut framing: Framing, mut codec: Codec, write_error: &'static str) -> Result<(), LayerError<E>>
+where
+    Writer: AsyncWrite + Unpin,
+    Framing: FrameCodec,
+    Codec: MessageEncoder<Message>,
+{
+    while let Some(message) = receiver.recv().await {
+        let bytes = framing.encode(codec.encode(message)).map_err(LayerError::Framing)?;
+        if !write_frame(&mut writer, &bytes, write_error).await? {
+            return Ok(());
+        }
+    }
+    match writer.shutdown().await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(source) => Err(LayerError::Io { context: write_error, source }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::framing::{FrameError, LengthDelimited, NewlineDelimited};
+    use crate::pattern_bridge::{ChannelToIoLayer, IoToChannelLayer, Utf8};
+    use std::time::Duration;
+    use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+    use tower::{service_fn, Layer, Service, ServiceBuilder};
+
+    /// Shouts every request back, until the caller is done
+    async fn shout((mut receiver, sender): (Receiver<String>, Sender<String>)) -> Result<(), &'static str> {
+        while let Some(message) = receiver.recv().await {
+            sender.send(message.to_uppercase()).await.map_err(|_| "send failed")?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_typed_client_to_typed_server() {
+        let server = ServiceBuilder::new()
+            .layer(IoToChannelLayer::new(Utf8, LengthDelimited::new(1024)))
+            .service(service_fn(shout));
+        let mut client = ServiceBuilder::new()
+            .layer(ChannelToIoLayer::new(Utf8, LengthDelimited::new(1024)))
+            .service(server);
+
+        let (request_sender, request_receiver) = channel::<String>(4);
+        let (response_sender, mut response_receiver) = channel::<String>(4);
+        let task = tokio::spawn(client.call((request_receiver, response_sender)));
+        for message in ["hello", "", "world"] {
+            request_sender.send(message.to_string()).await.unwrap();
+        }
+        // Closing our side closes the server's side, which ends the replies
+        drop(request_sender);
+        let mut responses = Vec::new();
+        while let Some(response) = response_receiver.recv().await {
+            responses.push(response);
+        }
+        assert_eq!(responses, ["HELLO", "", "WORLD"]);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_is_a_typed_error() {
+        let mut server = IoToChannelLayer::new(Utf8, NewlineDelimited::new(1024)).layer(service_fn(shout));
+        let (caller, layer_side) = duplex(1024);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(server.call(split(layer_side)));
+
+        caller_write.write_all(b"\xff\xfe\n").await.unwrap();
+        // The session ends, closing our side, without us closing anything
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        let result = task.await.unwrap();
+        assert!(matches!(result, Err(LayerError::Framing(FrameError::Malformed(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_inner_service_leaving_early_ends_the_session_cleanly() {
+        let mut server = IoToChannelLayer::new(Utf8, NewlineDelimited::new(1024)).layer(service_fn(|(mut receiver, sender): (Receiver<String>, Sender<String>)| async move {
+            let message = receiver.recv().await.ok_or("no message")?;
+            sender.send(message.to_uppercase()).await.map_err(|_| "send failed")
+        }));
+        let (caller, layer_side) = duplex(1024);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(server.call(split(layer_side)));
+
+        // Only the first line is answered, and our side is closed for us even though we keep writing
+        caller_write.write_all(b"one\ntwo\nthree\n").await.unwrap();
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"ONE\n");
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_stops_the_session() {
+        let timeout = Duration::from_millis(20);
+        let mut client = ChannelToIoLayer::<_, _, String, String>::new(Utf8, NewlineDelimited::new(1024))
+            .with_timeout(timeout)
+            .layer(service_fn(|_pipe| std::future::pending::<Result<(), &'static str>>()));
+        let (_request_sender, request_receiver) = channel::<String>(1);
+        let (response_sender, mut response_receiver) = channel::<String>(1);
+        let result = client.call((request_receiver, response_sender)).await;
+        assert!(matches!(result, Err(LayerError::Timeout(d)) if d == timeout));
+        // The caller is told the session is over
+        assert!(response_receiver.recv().await.is_none());
+    }
+}
@@ -1,21 +1,135 @@
 use crate::error::LayerError;
+use crate::session::run_session;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
 use tower::{Layer, Service};
 
+/// How many messages the layer holds for a side that is not keeping up, in each direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelCapacity {
+    /// Up to this many messages are queued, after which the sending side has to wait
+    Bounded(usize),
+    /// Every message is queued, so the sending side never waits, and memory is the limit
+    Unbounded,
+    /// Up to this many messages are queued, after which the oldest queued message is dropped to make room
+    DropOldest(usize),
+}
+
+impl Default for ChannelCapacity {
+    fn default() -> Self {
+        ChannelCapacity::Bounded(1)
+    }
+}
+
+impl ChannelCapacity {
+    /// Whether the queue cannot take another message without dropping one
+    fn is_full(&self, queued: usize) -> bool {
+        match self {
+            ChannelCapacity::Bounded(limit) | ChannelCapacity::DropOldest(limit) => queued >= *limit,
+            ChannelCapacity::Unbounded => false,
+        }
+    }
+}
+
+/// Counters for the messages flowing in one direction, across every session of a layer
+#[derive(Debug, Default)]
+pub struct DirectionMetrics {
+    received: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    queue_depth: AtomicUsize,
+    max_queue_depth: AtomicUsize,
+    blocked_nanos: AtomicU64,
+}
+
+impl DirectionMetrics {
+    /// Messages taken in from the sending side
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Messages passed on to the receiving side
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped to make room, which only happens with [ChannelCapacity::DropOldest]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Messages currently queued, waiting for the receiving side
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// The deepest the queue of any one session has been
+    pub fn max_queue_depth(&self) -> usize {
+        self.max_queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Total time messages spent waiting for the receiving side to make room
+    pub fn blocked(&self) -> Duration {
+        Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed))
+    }
+
+    fn queued(&self, depth: usize) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn dequeued(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The metrics of a [ChannelPatternLayer], shared by every service it creates
+#[derive(Debug, Default)]
+pub struct ChannelMetrics {
+    /// From the caller to the inner service
+    pub upstream: DirectionMetrics,
+    /// From the inner service back to the caller
+    pub downstream: DirectionMetrics,
+}
+
 /// ChannelPatternService is the service is similar to the IOPatternService
+/// It relays messages both ways until the caller or the inner service closes its side
 pub struct ChannelPatternService<InnerService, OutputType>
 where
     InnerService: Service<(Receiver<OutputType>, Sender<OutputType>)> + Clone + Send + 'static,
 {
     inner: InnerService,
+    capacity: ChannelCapacity,
+    timeout: Option<Duration>,
+    metrics: Arc<ChannelMetrics>,
     _phantom_output: PhantomData<OutputType>,
 }
 
+// Deriving Clone would also require OutputType to be Clone, even though we only hold it as PhantomData
+impl<InnerService, OutputType> Clone for ChannelPatternService<InnerService, OutputType>
+where
+    InnerService: Service<(Receiver<OutputType>, Sender<OutputType>)> + Clone + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        ChannelPatternService {
+            inner: self.inner.clone(),
+            capacity: self.capacity,
+            timeout: self.timeout,
+            metrics: self.metrics.clone(),
+            _phantom_output: PhantomData,
+        }
+    }
+}
+
 impl<InnerService, InputType, OutputType> Service<(Receiver<InputType>, Sender<InputType>)> for ChannelPatternService<InnerService, OutputType>
 where
 // We force the response to be (), but it could be generic and handled in some way
@@ -36,68 +150,144 @@ where
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send + 'static>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(|e| LayerError::InnerError(e))
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
     }
 
-    fn call(&mut self, (mut input_receiver, mut input_sender): (Receiver<InputType>, Sender<InputType>)) -> Self::Future {
+    fn call(&mut self, (input_receiver, input_sender): (Receiver<InputType>, Sender<InputType>)) -> Self::Future {
         // The implementation is similar to the IOPatternService
         // We are also doing the same translation of types as we have in the basic service
         let mut inner = self.inner.clone();
+        let capacity = self.capacity;
+        let timeout = self.timeout;
+        let metrics = self.metrics.clone();
         Box::pin(async move {
             // First we will create the channel pairs to communicate with the inner service
-            let (sx_svc, mut rx_this) = channel::<OutputType>(1);
+            // Messages are queued by the relays rather than in these channels, so they only need room for one
+            let (sx_svc, rx_this) = channel::<OutputType>(1);
             let (sx_this, rx_svc) = channel::<OutputType>(1);
 
             // Now we will spawn the inner service so it can process in parallel without blocking us
             // We don't need to declare the type explicitly, but it helps the IDE figure things out :)
             let task: JoinHandle<Result<InnerService::Response, InnerService::Error>> = tokio::spawn(inner.call((rx_svc, sx_svc)));
 
-            // Ideally, what follows would be in a loop, but we will simplify it for the example
-
-            // We ready from the input
-            let input_message = input_receiver.recv().await;
-            let input_message = input_message.ok_or(LayerError::ServiceLayerError("Failed to receive message from input receiver"))?;
-
-            // Send the translated message downstream to the inner service
-            sx_this.send(input_message.into()).await.map_err(|_| LayerError::ServiceLayerError("Failed to send message to inner service"))?;
-
-            // Now we wait for the response from the downstream service
-            let response = rx_this.recv().await.ok_or(LayerError::ServiceLayerError("Failed to receive response from inner service"))?;
-
-            // And finally send it back to the caller
-            input_sender.send(response.into()).await.map_err(|_| LayerError::ServiceLayerError("Failed to send response to caller"))?;
-
-            // Here we do some cleanup to make sure everything shuts down correctly
-            drop(input_sender);
-            drop(input_receiver);
-            drop(rx_this);
-            drop(sx_this);
-            task.await
-                .map_err(|_join_error| LayerError::ServiceLayerError("Failed to join inner service task"))?
-                .map_err(|e| LayerError::InnerError(e))?;
-
-            Ok(())
+            // Both directions are relayed at the same time and independently of each other, so the
+            // inner service can send several replies per message, or messages nobody asked for
+            let upstream = relay(input_receiver, sx_this, capacity, &metrics.upstream);
+            let downstream = relay(rx_this, input_sender, capacity, &metrics.downstream);
+            run_session(task, upstream, downstream, timeout).await
         })
     }
 }
 
-#[derive(Default)]
+/// Relays messages in order, translating each one, until every sender on the receiving side is gone.
+/// Messages are queued according to `capacity` while the receiving side is not ready for them.
+/// The sending side is dropped at the end, which is how the closure reaches the other side.
+/// If the other side has stopped receiving, there is nobody left to relay to, and the relay ends
+/// by dropping `receiver` so the closure also travels back the way the messages came.
+/// That is not an error, just as with the IO relay, and whatever had not reached the other side is dropped.
+async fn relay<From, To, E>(mut receiver: Receiver<From>, sender: Sender<To>, capacity: ChannelCapacity, metrics: &DirectionMetrics) -> Result<(), LayerError<E>>
+where
+    From: Into<To>,
+{
+    let mut queue = VecDeque::new();
+    let mut receiver_open = true;
+    // When the message at the front of the queue started waiting for the receiving side
+    let mut blocked_since = None;
+    loop {
+        if !receiver_open && queue.is_empty() {
+            break;
+        }
+        if !queue.is_empty() && blocked_since.is_none() {
+            blocked_since = Some(Instant::now());
+        }
+        let lossy = matches!(capacity, ChannelCapacity::DropOldest(_));
+        tokio::select! {
+            // Passing on what is queued comes before taking in more, so nothing is dropped while there is room downstream
+            biased;
+            permit = sender.reserve(), if !queue.is_empty() => {
+                let Ok(permit) = permit else { break };
+                if let Some(since) = blocked_since.take() {
+                    metrics.blocked_nanos.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                }
+                if let Some(message) = queue.pop_front() {
+                    metrics.dequeued();
+                    metrics.sent.fetch_add(1, Ordering::Relaxed);
+                    permit.send(message);
+                }
+            }
+            // We notice the other side leaving even while nothing is being sent to it
+            _ = sender.closed(), if queue.is_empty() => break,
+            message = receiver.recv(), if receiver_open && (lossy || !capacity.is_full(queue.len())) => {
+                let Some(message) = message else {
+                    receiver_open = false;
+                    continue;
+                };
+                // The queue stays blocked, so the timer keeps running for the message that takes its place
+                if capacity.is_full(queue.len()) && queue.pop_front().is_some() {
+                    metrics.dequeued();
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                queue.push_back(message.into());
+                metrics.queued(queue.len());
+            }
+        }
+    }
+    // Whatever is still queued will never be delivered
+    for _ in queue.drain(..) {
+        metrics.dequeued();
+    }
+    Ok(())
+}
+
 pub struct ChannelPatternLayer<OutputType>
 where
     OutputType: Send + 'static,
 {
+    capacity: ChannelCapacity,
+    timeout: Option<Duration>,
+    metrics: Arc<ChannelMetrics>,
     _phantom_output: PhantomData<OutputType>,
 }
 
+impl<OutputType> Default for ChannelPatternLayer<OutputType>
+where
+    OutputType: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<OutputType> ChannelPatternLayer<OutputType>
 where
     OutputType: Send + 'static,
 {
+    /// Queues one message in each direction, so a slow side quickly pushes back on the other
     pub fn new() -> Self {
+        Self::with_capacity(ChannelCapacity::default())
+    }
+
+    pub fn with_capacity(capacity: ChannelCapacity) -> Self {
+        assert!(!matches!(capacity, ChannelCapacity::Bounded(0) | ChannelCapacity::DropOldest(0)), "a channel capacity must allow at least one message");
         ChannelPatternLayer {
+            capacity,
+            timeout: None,
+            metrics: Arc::new(ChannelMetrics::default()),
             _phantom_output: PhantomData,
         }
     }
+
+    /// Limits how long each session may take. A session still going after `timeout` is stopped,
+    /// along with its inner service, and fails with [LayerError::Timeout].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The metrics of every service this layer creates, kept up to date as messages flow
+    pub fn metrics(&self) -> Arc<ChannelMetrics> {
+        self.metrics.clone()
+    }
 }
 
 impl<InnerService, OutputType> Layer<InnerService> for ChannelPatternLayer<OutputType>
@@ -110,7 +300,196 @@ where
     fn layer(&self, inner: InnerService) -> Self::Service {
         ChannelPatternService {
             inner,
+            capacity: self.capacity,
+            timeout: self.timeout,
+            metrics: self.metrics.clone(),
             _phantom_output: PhantomData,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::helper::{DataTypeA, DataTypeB};
+    use crate::pattern_chan::{ChannelCapacity, ChannelPatternLayer};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+    use tokio::sync::Notify;
+    use tower::{service_fn, Layer, Service};
+
+    /// Echoes every message, but only starts reading once `start` is notified
+    async fn slow_echo(start: Arc<Notify>, (mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)) -> Result<(), &'static str> {
+        start.notified().await;
+        while let Some(message) = receiver.recv().await {
+            sender.send(message).await.map_err(|_| "send failed")?;
+        }
+        Ok(())
+    }
+
+    /// Sends every value, `pause` apart, closes the caller's side once the inner service is started, and collects the replies
+    async fn session(layer: ChannelPatternLayer<DataTypeB>, values: impl IntoIterator<Item=u8>, pause: Duration) -> Vec<u8> {
+        let start = Arc::new(Notify::new());
+        let inner_start = start.clone();
+        let mut service = layer.layer(service_fn(move |pair| slow_echo(inner_start.clone(), pair)));
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+        for value in values {
+            sender.send(DataTypeA(value)).await.unwrap();
+            tokio::time::sleep(pause).await;
+        }
+        drop(sender);
+        start.notify_one();
+        let mut replies = Vec::new();
+        while let Some(reply) = receiver.recv().await {
+            replies.push(reply.0);
+        }
+        task.await.unwrap().unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_and_multiple_replies_in_order() {
+        let mut service = ChannelPatternLayer::<DataTypeB>::new().layer(service_fn(|(mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)| async move {
+            // A greeting before anyone asked, then every message is acknowledged twice
+            sender.send(DataTypeB(0)).await.map_err(|_| "send failed")?;
+            while let Some(message) = receiver.recv().await {
+                sender.send(DataTypeB(message.0)).await.map_err(|_| "send failed")?;
+                sender.send(DataTypeB(message.0 + 100)).await.map_err(|_| "send failed")?;
+            }
+            Ok::<(), &'static str>(())
+        }));
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+
+        assert_eq!(receiver.recv().await.unwrap().0, 0);
+        for value in 1..=3 {
+            sender.send(DataTypeA(value)).await.unwrap();
+        }
+        // Closing our side ends the inner service's loop, which then closes its side
+        drop(sender);
+        let mut replies = Vec::new();
+        while let Some(reply) = receiver.recv().await {
+            replies.push(reply.0);
+        }
+        assert_eq!(replies, [1, 101, 2, 102, 3, 103]);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closure_propagates_both_ways() {
+        // The inner service leaves on its own, while the caller still has its side open
+        let mut service = ChannelPatternLayer::<DataTypeB>::new().layer(service_fn(|_: (Receiver<DataTypeB>, Sender<DataTypeB>)| async {
+            Ok::<(), &'static str>(())
+        }));
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+        assert!(receiver.recv().await.is_none());
+        task.await.unwrap().unwrap();
+        assert!(sender.send(DataTypeA(1)).await.is_err());
+
+        // The caller stops listening, and the inner service notices even though it never sends
+        let mut service = ChannelPatternLayer::<DataTypeB>::new().layer(service_fn(|(_receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)| async move {
+            sender.closed().await;
+            Ok::<(), &'static str>(())
+        }));
+        let (_sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+        drop(receiver);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_the_latest_messages() {
+        let layer = ChannelPatternLayer::with_capacity(ChannelCapacity::DropOldest(2));
+        let metrics = layer.metrics();
+        // The first message is handed to the inner service straight away, and only the two latest
+        // of the rest survive in the queue. The inner service does not read anything until every
+        // message has been sent, so the queue is blocked from the second message onwards.
+        let pause = Duration::from_millis(10);
+        assert_eq!(session(layer, 0..5, pause).await, [0, 3, 4]);
+        assert_eq!(metrics.upstream.received(), 5);
+        assert_eq!(metrics.upstream.sent(), 3);
+        assert_eq!(metrics.upstream.dropped(), 2);
+        assert_eq!(metrics.upstream.max_queue_depth(), 2);
+        assert_eq!(metrics.upstream.queue_depth(), 0);
+        assert_eq!(metrics.downstream.sent(), 3);
+        assert!(metrics.upstream.blocked() >= pause * 3, "{:?}", metrics.upstream.blocked());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_queues_everything() {
+        let layer = ChannelPatternLayer::with_capacity(ChannelCapacity::Unbounded);
+        let metrics = layer.metrics();
+        assert_eq!(session(layer, 0..100, Duration::ZERO).await, (0..100).collect::<Vec<u8>>());
+        assert_eq!(metrics.upstream.max_queue_depth(), 99);
+        assert_eq!(metrics.upstream.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bounded_records_time_blocked() {
+        let layer = ChannelPatternLayer::with_capacity(ChannelCapacity::Bounded(1));
+        let metrics = layer.metrics();
+        let mut service = layer.layer(service_fn(|(mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)| async move {
+            // Slow to start reading, so the second message has to wait
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            while let Some(message) = receiver.recv().await {
+                sender.send(message).await.map_err(|_| "send failed")?;
+            }
+            Ok::<(), &'static str>(())
+        }));
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+        for value in 0..3 {
+            sender.send(DataTypeA(value)).await.unwrap();
+        }
+        drop(sender);
+        while receiver.recv().await.is_some() {}
+        task.await.unwrap().unwrap();
+        assert!(metrics.upstream.blocked() >= Duration::from_millis(25), "{:?}", metrics.upstream.blocked());
+        assert_eq!(metrics.upstream.sent(), 3);
+        assert_eq!(metrics.upstream.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_inner_service_leaving_early_ends_the_session_cleanly() {
+        // Answers the first message, then leaves while more are on their way
+        let mut service = ChannelPatternLayer::<DataTypeB>::new().layer(service_fn(|(mut receiver, sender): (Receiver<DataTypeB>, Sender<DataTypeB>)| async move {
+            let message = receiver.recv().await.ok_or("no message")?;
+            sender.send(message).await.map_err(|_| "send failed")
+        }));
+        let (sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let task = tokio::spawn(service.call((svc_receiver, svc_sender)));
+        for value in 0..5 {
+            // Once the session is over there is nobody left to take the rest
+            let _ = sender.send(DataTypeA(value)).await;
+        }
+        let mut replies = Vec::new();
+        while let Some(reply) = receiver.recv().await {
+            replies.push(reply.0);
+        }
+        assert_eq!(replies, [0]);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_stops_the_session() {
+        let timeout = Duration::from_millis(20);
+        let mut service = ChannelPatternLayer::<DataTypeB>::new().with_timeout(timeout).layer(service_fn(|_: (Receiver<DataTypeB>, Sender<DataTypeB>)| {
+            std::future::pending::<Result<(), &'static str>>()
+        }));
+        let (_sender, svc_receiver) = channel::<DataTypeA>(1);
+        let (svc_sender, mut receiver) = channel::<DataTypeA>(1);
+        let result = service.call((svc_receiver, svc_sender)).await;
+        assert!(matches!(result, Err(LayerError::Timeout(d)) if d == timeout));
+        // The caller is told the session is over
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::error::LayerError;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::marker::PhantomData;
 use std::ops::AddAssign;
@@ -9,95 +11,299 @@ use tower::{Layer, Service};
 /// The ServiceHandler works similarly to how a tower Service would work
 /// The difference is that using this pattern you can combine it with other layers in between
 /// instead of relying just on the tower Service handling the message
+/// Handlers can fail, and the peer on the other side of a handler can go away, so every operation
+/// reports how it went instead of panicking or waiting forever
 pub trait ServiceHandler<Message> {
-    fn send_message(&mut self, msg: Message) -> impl Future<Output=()> + Send;
-    fn receive_message(&mut self) -> impl Future<Output=Message> + Send;
+    type Error: Error + Send + Sync + 'static;
+
+    fn send_message(&mut self, msg: Message) -> impl Future<Output=Result<(), Self::Error>> + Send;
+    /// Resolves to `None` once the peer has closed its side and no more messages will arrive
+    fn receive_message(&mut self) -> impl Future<Output=Result<Option<Message>, Self::Error>> + Send;
+    /// Tells the peer that no more messages will be sent
+    fn close(&mut self) -> impl Future<Output=Result<(), Self::Error>> + Send;
+}
+
+/// Why an exchange between handlers ended early
+#[derive(Debug)]
+pub enum HandlerError {
+    /// A handler's peer closed its side while a message was still expected
+    Closed,
+    /// A handler failed
+    Failed(Box<dyn Error + Send + Sync>),
+}
+
+impl HandlerError {
+    pub fn failed(error: impl Error + Send + Sync + 'static) -> Self {
+        HandlerError::Failed(Box::new(error))
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::Closed => write!(f, "handler closed while a message was expected"),
+            HandlerError::Failed(e) => write!(f, "handler failed: {e}"),
+        }
+    }
 }
 
-pub struct HandlerPatternService<InnerService, OutputHandler, Message>
+impl Error for HandlerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HandlerError::Closed => None,
+            HandlerError::Failed(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// Receives the next message from `handler`, treating the end of the stream as an error.
+/// This is for exchanges that cannot continue without a reply.
+pub async fn expect_message<Message, Handler>(handler: &mut Handler) -> Result<Message, HandlerError>
+where
+    Handler: ServiceHandler<Message>,
+{
+    handler.receive_message().await.map_err(HandlerError::failed)?.ok_or(HandlerError::Closed)
+}
+
+/// Drives the exchange between the caller's handler and the inner service's handler.
+/// The mediator decides how many messages go each way, and when the exchange is over.
+/// The layer clones the mediator for every call, so its fields start out the same in each exchange.
+pub trait Mediator<Message, InputHandler, OutputHandler>: Clone + Send + 'static {
+    fn mediate(&mut self, input_handler: InputHandler, output_handler: OutputHandler) -> impl Future<Output=Result<(), HandlerError>> + Send;
+}
+
+/// A mediator can be written inline, as a closure returning a future
+impl<F, Fut, Message, InputHandler, OutputHandler> Mediator<Message, InputHandler, OutputHandler> for F
+where
+    F: FnMut(InputHandler, OutputHandler) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output=Result<(), HandlerError>> + Send,
+{
+    fn mediate(&mut self, input_handler: InputHandler, output_handler: OutputHandler) -> impl Future<Output=Result<(), HandlerError>> + Send {
+        self(input_handler, output_handler)
+    }
+}
+
+/// Sends one message from the caller downstream, and replies with the sum of the next two messages
+/// that come back, then closes both handlers
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddTwoReplies;
+
+impl<Message, InputHandler, OutputHandler> Mediator<Message, InputHandler, OutputHandler> for AddTwoReplies
+where
+    InputHandler: ServiceHandler<Message> + Send,
+    OutputHandler: ServiceHandler<Message> + Send,
+    Message: AddAssign + Send,
+{
+    async fn mediate(&mut self, mut input_handler: InputHandler, mut output_handler: OutputHandler) -> Result<(), HandlerError> {
+        // Lets receive some input and send it to the downstream service
+        let input = expect_message(&mut input_handler).await?;
+        output_handler.send_message(input).await.map_err(HandlerError::failed)?;
+
+        // Now lets get some output from the downstream service and modify it
+        let mut first = expect_message(&mut output_handler).await?;
+        let second = expect_message(&mut output_handler).await?;
+        first += second;
+
+        // Now we will send it back to the input handler, and let both sides know we are done
+        input_handler.send_message(first).await.map_err(HandlerError::failed)?;
+        output_handler.close().await.map_err(HandlerError::failed)?;
+        input_handler.close().await.map_err(HandlerError::failed)
+    }
+}
+
+pub struct HandlerPatternService<InnerService, OutputHandler, Message, M = AddTwoReplies>
 where
     InnerService: Service<(), Response=OutputHandler>,
     OutputHandler: ServiceHandler<Message>,
 {
     inner: InnerService,
+    mediator: M,
     _phantom_output_handler: PhantomData<OutputHandler>,
     _phantom_message: PhantomData<Message>,
 }
 
-impl<InnerService, InputHandler, OutputHandler, Message> Service<InputHandler> for HandlerPatternService<InnerService, OutputHandler, Message>
+impl<InnerService, InputHandler, OutputHandler, Message, M> Service<InputHandler> for HandlerPatternService<InnerService, OutputHandler, Message, M>
 where
     InnerService: Service<(), Response=OutputHandler> + Clone + Send + 'static,
     InnerService::Future: Future<Output=Result<OutputHandler, InnerService::Error>> + Send,
     InputHandler: ServiceHandler<Message> + Send + 'static,
 // We do not need to declare 'static because we are no longer spawning
     OutputHandler: ServiceHandler<Message> + Send,
-    Message: AddAssign + Send,
+    Message: Send,
+    M: Mediator<Message, InputHandler, OutputHandler>,
 {
     type Response = ();
     type Error = LayerError<InnerService::Error>;
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(|e| LayerError::InnerError(e))
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
     }
 
-    fn call(&mut self, mut input_handler: InputHandler) -> Self::Future {
+    fn call(&mut self, input_handler: InputHandler) -> Self::Future {
         let mut inner = self.inner.clone();
+        let mut mediator = self.mediator.clone();
         Box::pin(async move {
             // We get the handler from the downstream service
             // Normally, you wouldn't do this, as it could be implemented by a tower Service internally
             // but by having it as a layer, you can do transformations to the message before it is
             // received by the mediating layer
-            let mut inner_handler = inner.call(()).await.map_err(|e| LayerError::InnerError(e))?;
-
-            // Lets receive some input and send it to the downstream service
-            let input = input_handler.receive_message().await;
-            inner_handler.send_message(input).await;
+            let inner_handler = inner.call(()).await.map_err(LayerError::InnerError)?;
 
-            // Now lets get some output from the downstream service and modify it
-            let mut first = inner_handler.receive_message().await;
-            let second = inner_handler.receive_message().await;
-            first += second;
-
-            // Now we will send it back to the input handler
-            input_handler.send_message(first).await;
-            Ok(())
+            // The mediator takes it from here, for as many messages as it needs
+            mediator.mediate(input_handler, inner_handler).await.map_err(LayerError::Handler)
         })
     }
 }
 
-pub struct HandlerPatternLayer<Message>
+pub struct HandlerPatternLayer<Message, M = AddTwoReplies>
 where
-    Message: AddAssign + Send,
+    Message: Send,
 {
+    mediator: M,
     _phantom_message: PhantomData<Message>,
 }
 
 impl<Message> HandlerPatternLayer<Message>
 where
-    Message: AddAssign + Send,
+    Message: Send,
 {
+    /// Uses the [AddTwoReplies] mediator
     pub fn new() -> Self {
         HandlerPatternLayer {
+            mediator: AddTwoReplies,
             _phantom_message: PhantomData,
         }
     }
 }
 
-impl<InnerService, OutputHandler, Message> Layer<InnerService> for HandlerPatternLayer<Message>
+impl<Message, M> HandlerPatternLayer<Message, M>
+where
+    Message: Send,
+    M: Clone + Send + 'static,
+{
+    /// Lets `mediator` drive every exchange between the two handlers
+    pub fn with_mediator(mediator: M) -> Self {
+        HandlerPatternLayer {
+            mediator,
+            _phantom_message: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, OutputHandler, Message, M> Layer<InnerService> for HandlerPatternLayer<Message, M>
 where
     InnerService: Service<(), Response=OutputHandler> + Clone + Send + 'static,
     InnerService::Future: Future<Output=Result<OutputHandler, InnerService::Error>> + Send,
     OutputHandler: ServiceHandler<Message> + Send,
-    Message: AddAssign + Send,
+    Message: Send,
+    M: Clone + Send + 'static,
 {
-    type Service = HandlerPatternService<InnerService, OutputHandler, Message>;
+    type Service = HandlerPatternService<InnerService, OutputHandler, Message, M>;
 
     fn layer(&self, inner: InnerService) -> Self::Service {
         HandlerPatternService {
             inner,
+            mediator: self.mediator.clone(),
             _phantom_output_handler: PhantomData,
             _phantom_message: PhantomData,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::helper::IncrementingHandler;
+    use crate::pattern_handler::{expect_message, HandlerError, HandlerPatternLayer, ServiceHandler};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::atomic::Ordering;
+    use tower::{service_fn, Layer, Service};
+
+    /// Hands out the messages it was given, then reports that its peer has closed.
+    /// Sending fails once it has been closed.
+    #[derive(Clone)]
+    struct ScriptedHandler {
+        incoming: VecDeque<u32>,
+        closed: bool,
+    }
+
+    impl ScriptedHandler {
+        fn new(incoming: impl IntoIterator<Item=u32>) -> Self {
+            ScriptedHandler { incoming: incoming.into_iter().collect(), closed: false }
+        }
+    }
+
+    impl ServiceHandler<u32> for ScriptedHandler {
+        type Error = io::Error;
+
+        async fn send_message(&mut self, _msg: u32) -> Result<(), io::Error> {
+            match self.closed {
+                true => Err(io::ErrorKind::BrokenPipe.into()),
+                false => Ok(()),
+            }
+        }
+
+        async fn receive_message(&mut self) -> Result<Option<u32>, io::Error> {
+            Ok(self.incoming.pop_front())
+        }
+
+        async fn close(&mut self) -> Result<(), io::Error> {
+            self.closed = true;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_mediator_adds_two_replies() {
+        let inner = IncrementingHandler::new(0);
+        let inner_for_service = inner.clone();
+        let mut service = HandlerPatternLayer::new().layer(service_fn(move |_: ()| {
+            let inner = inner_for_service.clone();
+            async move { Ok::<_, ()>(inner) }
+        }));
+        let mut caller = IncrementingHandler::new(10);
+        service.call(caller.clone()).await.unwrap();
+        // 10 is sent down, and 10 + 11 comes back
+        assert_eq!(caller.receive_message().await.unwrap(), Some(21));
+        assert_eq!(inner.value.load(Ordering::SeqCst), 12);
+    }
+
+    #[tokio::test]
+    async fn test_mediator_with_a_loop_and_early_termination() {
+        // Counts down from whatever the caller sends, and stops early once it reaches a multiple of 5
+        let mediator = |mut caller: IncrementingHandler, mut inner: IncrementingHandler| async move {
+            let mut remaining = expect_message(&mut caller).await?;
+            let mut rounds = 0;
+            while remaining > 0 && remaining % 5 != 0 {
+                inner.send_message(remaining - 1).await.map_err(HandlerError::failed)?;
+                remaining = inner.value.load(Ordering::SeqCst);
+                rounds += 1;
+            }
+            caller.send_message(rounds).await.map_err(HandlerError::failed)
+        };
+        let mut service = HandlerPatternLayer::with_mediator(mediator).layer(service_fn(|_: ()| async { Ok::<_, ()>(IncrementingHandler::new(0)) }));
+        let caller = IncrementingHandler::new(13);
+        service.call(caller.clone()).await.unwrap();
+        assert_eq!(caller.value.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_handler_closing_and_failing_are_reported() {
+        // The inner handler only ever replies once, but the default mediator needs two replies
+        let mut service = HandlerPatternLayer::new().layer(service_fn(|_: ()| async { Ok::<_, ()>(ScriptedHandler::new([1])) }));
+        let result = service.call(ScriptedHandler::new([5])).await;
+        assert!(matches!(result, Err(LayerError::Handler(HandlerError::Closed))));
+
+        // The caller closes its handler before the reply can be sent
+        let mediator = |mut caller: ScriptedHandler, _inner: ScriptedHandler| async move {
+            caller.close().await.map_err(HandlerError::failed)?;
+            caller.send_message(1).await.map_err(HandlerError::failed)
+        };
+        let mut service = HandlerPatternLayer::with_mediator(mediator).layer(service_fn(|_: ()| async { Ok::<_, ()>(ScriptedHandler::new([])) }));
+        let result = service.call(ScriptedHandler::new([])).await;
+        let Err(LayerError::Handler(HandlerError::Failed(e))) = result else { panic!() };
+        assert_eq!(e.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,65 +1,152 @@
 use crate::error::LayerError;
-use crate::pattern_handler::ServiceHandler;
+use crate::pattern_handler::{expect_message, HandlerError, ServiceHandler};
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tower::{Layer, Service};
 
+/// Where a message goes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    /// To the handler, whose reply goes to the inner service, whose response goes back to the handler
+    Exchange,
+    /// To the inner service, whose response is sent to the handler
+    Inner,
+    /// Straight to the handler, leaving the inner service out of it
+    Handler,
+    /// To the handler, and so is every later message of the session, without asking the classifier again
+    Upgrade,
+}
+
+/// Decides where each message goes, until the session is upgraded
+pub trait Classifier<Message>: Clone + Send + 'static {
+    fn classify(&mut self, msg: &Message) -> Route;
+}
+
+/// Routing that only looks at the message itself fits in a closure
+impl<F, Message> Classifier<Message> for F
+where
+    F: FnMut(&Message) -> Route + Clone + Send + 'static,
+{
+    fn classify(&mut self, msg: &Message) -> Route {
+        self(msg)
+    }
+}
+
+/// Takes every message through the full [Route::Exchange]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AlwaysExchange;
+
+impl<Message> Classifier<Message> for AlwaysExchange {
+    fn classify(&mut self, _msg: &Message) -> Route {
+        Route::Exchange
+    }
+}
+
 /// The injected pattern service contains a handler that can be used to interact with the protocol
 /// You would use this pattern in situations where you might have an optional protocol upgrade, that
 /// you don't necessarily want to send to the downstream service.
 /// So for example you could have an http service that sends synchronous requests downstream, but if
 /// it upgrades to websocket or some other protocol, then it gets delegated to the internal handler
-pub struct InjectedPatternService<InnerService, Handler, Message>
+/// The classifier decides the [Route] of each message. Every service the layer creates
+/// is its own session, so an upgrade is shared by the clones of a service, but not by other services.
+pub struct InjectedPatternService<InnerService, Handler, Message, C = AlwaysExchange>
 where
     InnerService: Service<Message, Response=Message> + Clone + Send,
     Handler: ServiceHandler<Message> + Clone + Send,
 {
     inner: InnerService,
     handler: Handler,
+    classifier: C,
+    upgraded: Arc<AtomicBool>,
     _phantom_message: PhantomData<Message>,
 }
 
-impl<InnerService, Handler, Message> Service<Message> for InjectedPatternService<InnerService, Handler, Message>
+impl<InnerService, Handler, Message, C> Clone for InjectedPatternService<InnerService, Handler, Message, C>
+where
+    InnerService: Service<Message, Response=Message> + Clone + Send,
+    Handler: ServiceHandler<Message> + Clone + Send,
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        InjectedPatternService {
+            inner: self.inner.clone(),
+            handler: self.handler.clone(),
+            classifier: self.classifier.clone(),
+            upgraded: self.upgraded.clone(),
+            _phantom_message: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, Handler, Message, C> InjectedPatternService<InnerService, Handler, Message, C>
+where
+    InnerService: Service<Message, Response=Message> + Clone + Send,
+    Handler: ServiceHandler<Message> + Clone + Send,
+{
+    /// Whether this session has been upgraded, so everything now goes to the handler
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded.load(Ordering::SeqCst)
+    }
+}
+
+impl<InnerService, Handler, Message, C> Service<Message> for InjectedPatternService<InnerService, Handler, Message, C>
 where
     InnerService: Service<Message, Response=Message> + Clone + Send + 'static,
     InnerService::Error: Send + 'static,
     InnerService::Future: Send + 'static,
     Handler: ServiceHandler<Message> + Clone + Send + 'static,
     Message: Send + 'static,
+    C: Classifier<Message>,
 {
     type Response = ();
     type Error = LayerError<InnerService::Error>;
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(|e| LayerError::InnerError(e))
+        self.inner.poll_ready(cx).map_err(LayerError::InnerError)
     }
 
     fn call(&mut self, req: Message) -> Self::Future {
+        // Once upgraded, the classifier is not consulted anymore
+        let route = match self.is_upgraded() {
+            true => Route::Handler,
+            false => self.classifier.classify(&req),
+        };
+        if route == Route::Upgrade {
+            self.upgraded.store(true, Ordering::SeqCst);
+        }
         let mut inner = self.inner.clone();
         let mut handler = self.handler.clone();
         Box::pin(async move {
-            // We send whatever input we have to the internal handler
-            handler.send_message(req).await;
-            // We receive the response from the internal handler
-            let response = handler.receive_message().await;
-            // We send the response to the inner service
-            let response = inner.call(response).await.map_err(|e| LayerError::InnerError(e))?;
-            // We send the final result back to the internal handler
-            handler.send_message(response).await;
-            Ok(())
+            let message = match route {
+                Route::Exchange => {
+                    // We send whatever input we have to the internal handler
+                    handler.send_message(req).await.map_err(|e| LayerError::Handler(HandlerError::failed(e)))?;
+                    // We receive the response from the internal handler, and send it to the inner service
+                    let response = expect_message(&mut handler).await.map_err(LayerError::Handler)?;
+                    inner.call(response).await.map_err(LayerError::InnerError)?
+                }
+                // The inner service handles the message, and the handler delivers its response
+                Route::Inner => inner.call(req).await.map_err(LayerError::InnerError)?,
+                // The handler takes over the message, including the one that caused the upgrade
+                Route::Handler | Route::Upgrade => req,
+            };
+            handler.send_message(message).await.map_err(|e| LayerError::Handler(HandlerError::failed(e)))
         })
     }
 }
 
-pub struct InjectedPatternLayer<Handler, Message>
+pub struct InjectedPatternLayer<Handler, Message, C = AlwaysExchange>
 where
     Handler: ServiceHandler<Message> + Clone + Send + 'static,
     Message: Send + 'static,
 {
     handler: Handler,
+    classifier: C,
     _phantom_message: PhantomData<Message>,
 }
 
@@ -68,29 +155,94 @@ where
     Handler: ServiceHandler<Message> + Clone + Send + 'static,
     Message: Send + 'static,
 {
+    /// Takes every message through the handler and the inner service, see [Route::Exchange]
     pub fn new(handler: Handler) -> Self {
         InjectedPatternLayer {
             handler,
+            classifier: AlwaysExchange,
             _phantom_message: PhantomData,
         }
     }
 }
 
-impl<InnerService, Handler, Message> Layer<InnerService> for InjectedPatternLayer<Handler, Message>
+impl<Handler, Message, C> InjectedPatternLayer<Handler, Message, C>
+where
+    Handler: ServiceHandler<Message> + Clone + Send + 'static,
+    Message: Send + 'static,
+    C: Classifier<Message>,
+{
+    /// Lets `classifier` decide where each message goes
+    pub fn with_classifier<NewClassifier>(self, classifier: NewClassifier) -> InjectedPatternLayer<Handler, Message, NewClassifier>
+    where
+        NewClassifier: Classifier<Message>,
+    {
+        InjectedPatternLayer {
+            handler: self.handler,
+            classifier,
+            _phantom_message: PhantomData,
+        }
+    }
+}
+
+impl<InnerService, Handler, Message, C> Layer<InnerService> for InjectedPatternLayer<Handler, Message, C>
 where
     InnerService: Service<Message, Response=Message> + Clone + Send + 'static,
     InnerService::Error: Send + 'static,
     InnerService::Future: Send + 'static,
     Handler: ServiceHandler<Message> + Clone + Send + 'static,
     Message: Send + 'static,
+    C: Classifier<Message>,
 {
-    type Service = InjectedPatternService<InnerService, Handler, Message>;
+    type Service = InjectedPatternService<InnerService, Handler, Message, C>;
 
     fn layer(&self, inner: InnerService) -> Self::Service {
         InjectedPatternService {
             inner,
             handler: self.handler.clone(),
+            classifier: self.classifier.clone(),
+            // Every service starts a new session, which has not been upgraded yet
+            upgraded: Arc::new(AtomicBool::new(false)),
             _phantom_message: PhantomData,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handlers::ChannelHandler;
+    use crate::pattern_injected::{InjectedPatternLayer, Route};
+    use tokio::sync::mpsc::channel;
+    use tower::{service_fn, Layer, Service};
+
+    #[tokio::test]
+    async fn test_routes_per_message_and_upgrades_per_session() {
+        let (handler_sender, mut from_handler) = channel::<u32>(8);
+        let (_to_handler, handler_receiver) = channel::<u32>(8);
+        // Odd numbers skip the inner service, and zero upgrades the session
+        let layer = InjectedPatternLayer::new(ChannelHandler::new(handler_sender, handler_receiver)).with_classifier(|value: &u32| match value {
+            0 => Route::Upgrade,
+            value if value % 2 == 1 => Route::Handler,
+            _ => Route::Inner,
+        });
+        let inner = service_fn(|value: u32| async move { Ok::<_, ()>(value * 10) });
+        let mut session = layer.layer(inner);
+        let mut other_session = layer.layer(inner);
+
+        for value in [2, 3, 0, 4, 5] {
+            session.call(value).await.unwrap();
+        }
+        assert!(session.is_upgraded());
+        // After the upgrade, even numbers no longer reach the inner service
+        let mut delivered = Vec::new();
+        for _ in 0..5 {
+            delivered.push(from_handler.recv().await.unwrap());
+        }
+        assert_eq!(delivered, [20, 3, 0, 4, 5]);
+
+        // Other sessions are not upgraded, but clones of the upgraded one are
+        assert!(session.clone().is_upgraded());
+        assert!(!other_session.is_upgraded());
+        other_session.call(4).await.unwrap();
+        assert_eq!(from_handler.recv().await.unwrap(), 40);
+    }
+}
@@ -1,30 +1,50 @@
 use crate::error::LayerError;
+use crate::framing::{FrameCodec, Raw};
+use crate::session::run_session;
+use crate::transform::{ByteTransform, Reverse};
 use std::future::Future;
+use std::io::ErrorKind;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{simplex, AsyncReadExt, AsyncWriteExt, ReadHalf, SimplexStream, WriteHalf};
+use std::time::Duration;
+use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
 use tokio::task::JoinHandle;
 use tower::{Layer, Service};
 
-const MAX_BUF_SIZE: usize = 1024;
+pub(crate) const MAX_BUF_SIZE: usize = 1024;
 
-pub struct IoPatternService<InnerService>
+#[derive(Clone)]
+pub struct IoPatternService<InnerService, Upstream = Reverse, Downstream = Reverse, OuterCodec = Raw, InnerCodec = Raw>
 where
 // We are explicit with the types, since we know the implementation we are providing downstream
 // However, the downstream service (such as another instance of this layer) can be generic
-    InnerService: Service<(ReadHalf<SimplexStream>, WriteHalf<SimplexStream>), Response=()>,
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()>,
 {
     inner: InnerService,
+    // Applied to what the caller sends to the inner service
+    upstream: Upstream,
+    // Applied to what the inner service sends back to the caller
+    downstream: Downstream,
+    // How the bytes from and to the caller are split into frames
+    outer_codec: OuterCodec,
+    // How the bytes from and to the inner service are split into frames
+    inner_codec: InnerCodec,
+    // How long a session may take, if it is limited at all
+    timeout: Option<Duration>,
 }
 
-impl<InnerService, Reader, Writer> Service<(Reader, Writer)> for IoPatternService<InnerService>
+impl<InnerService, Upstream, Downstream, OuterCodec, InnerCodec, Reader, Writer> Service<(Reader, Writer)> for IoPatternService<InnerService, Upstream, Downstream, OuterCodec, InnerCodec>
 where
-    InnerService: Service<(ReadHalf<SimplexStream>, WriteHalf<SimplexStream>), Response=()> + Clone + Send + 'static,
+    Upstream: ByteTransform,
+    Downstream: ByteTransform,
+    OuterCodec: FrameCodec,
+    InnerCodec: FrameCodec,
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()> + Clone + Send + 'static,
     InnerService::Future: Future<Output=Result<InnerService::Response, InnerService::Error>> + Send + 'static,
     InnerService::Error: Send + 'static,
 // We need Unpin because otherwise we cannot access the methods of these traits
-    Reader: AsyncReadExt + Send + Unpin + 'static,
-    Writer: AsyncWriteExt + Send + Unpin + 'static,
+    Reader: AsyncRead + Send + Unpin + 'static,
+    Writer: AsyncWrite + Send + Unpin + 'static,
 {
     // Since all communication is done via the readers and writers, there isn't really a need for a return type
     type Response = ();
@@ -32,84 +52,397 @@ where
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map(|result| result.map_err(|err| err.into()))
+        self.inner.poll_ready(cx).map(|result| result.map_err(LayerError::InnerError))
     }
 
-    fn call(&mut self, (mut input_reader, mut input_writer): (Reader, Writer)) -> Self::Future {
+    fn call(&mut self, (input_reader, input_writer): (Reader, Writer)) -> Self::Future {
         let mut inner = self.inner.clone();
+        // Every session gets its own copy of the transforms, so state does not leak between sessions
+        let upstream_transform = self.upstream.clone();
+        let downstream_transform = self.downstream.clone();
+        let outer_codec = self.outer_codec.clone();
+        let inner_codec = self.inner_codec.clone();
+        let timeout = self.timeout;
         Box::pin(async move {
-            // We create the read/write pairs that we will use to communicate with the downstream service
-            let (read_svc, mut write_this) = simplex(MAX_BUF_SIZE);
-            let (mut read_this, write_svc) = simplex(MAX_BUF_SIZE);
+            // We create the pipe that we will use to communicate with the downstream service.
+            // A duplex stream (unlike a simplex one) tells the other side when it has been dropped,
+            // so we notice when the downstream service is done, even if it never shuts down cleanly
+            let (this_side, svc_side) = duplex(MAX_BUF_SIZE);
+            let (read_this, write_this) = split(this_side);
+            let (read_svc, write_svc) = split(svc_side);
 
             // Now we spawn the downstream inner service because otherwise we would need to poll it to make it progress
             // Calling await on it directly would block the current task, preventing us from relaying messages
             // Because we have so many generics, my IDE isn't prompting with types, so I declared them explicitly here.
             let task: JoinHandle<Result<InnerService::Response, InnerService::Error>> = tokio::spawn(inner.call((read_svc, write_svc)));
 
-            // Ideally everything below would be a loop, but we won't bother with that
-            // We would need to handle more conditions than we would like for the purpose of the example
-
-            // Read from the layer input
-            let mut input_read_buffer = [0u8; 1024];
-            let result_sz = input_reader.read(&mut input_read_buffer).await;
-            let sz = match result_sz {
-                Ok(0) | Err(_) => {
-                    // The other side has closed the connection
-                    Err(LayerError::ServiceLayerError("Failed to read from input reader"))
-                }
-                Ok(sz) => {
-                    Ok(sz)
-                }
-            }?;
-
-            // Now we will reverse the input we received and send it down to the inner service
-            let reversed: Vec<u8> = input_read_buffer[..sz].iter().rev().cloned().collect();
-            write_this.write_all(&reversed).await.map_err(|_| LayerError::ServiceLayerError("Failed to write to inner service"))?;
-
-            // Let's now read the response from the downstream service
-            let sz = read_this.read(&mut input_read_buffer).await.map_err(|_| LayerError::ServiceLayerError("Failed to read from inner service"))?;
-            if sz == 0 {
-                // The other side has closed the connection
-                return Err(LayerError::ServiceLayerError("Failed to read from inner service"));
-            }
-            // Let's reverse what the downstream service sent
-            let reversed: Vec<u8> = input_read_buffer[..sz].iter().rev().cloned().collect();
-
-            // Finally we will write this back to our input
-            input_writer.write_all(&reversed).await.map_err(|_| LayerError::ServiceLayerError("Failed to write to input writer"))?;
-
-            // Technically, we should properly handle the spawned task
-            // Thankfully, because we aren't doing things in a loop, we can simplify.
-            // By dropping all the handlers we own, the invoking function and the downstream service
-            // will know to terminate
-            drop(input_reader);
-            drop(input_writer);
-            drop(read_this);
-            drop(write_this);
-
-            // Let's politely wait for the task to complete in case it has errored
-            let inner_service_result = task.await.map_err(|_| LayerError::ServiceLayerError("Task failed"))?;
-            inner_service_result.map_err(|err| LayerError::InnerError(err))?;
-            Ok(())
+            // Both directions are pumped at the same time, so neither side has to wait for the
+            // other to finish talking before it gets a reply
+            let upstream = relay(
+                (input_reader, outer_codec.clone()),
+                (write_this, inner_codec.clone()),
+                upstream_transform,
+                "Failed to read from input reader",
+                "Failed to write to inner service",
+            );
+            let downstream = relay(
+                (read_this, inner_codec),
+                (input_writer, outer_codec),
+                downstream_transform,
+                "Failed to read from inner service",
+                "Failed to write to input writer",
+            );
+            run_session(task, upstream, downstream, timeout).await
         })
     }
 }
 
+/// Relays frames from `reader` to `writer`, transforming every frame on the way, until `reader` reaches EOF.
+/// Each side brings its own codec, so the bytes are split into frames the way the reading side
+/// sent them, and framed again the way the writing side expects them.
+/// The EOF is passed on by shutting down `writer`, so the other side sees the half-close and can
+/// still reply in the other direction.
+/// If the other side has gone away there is nobody left to relay to, which ends the relay but is not an error.
+async fn relay<Reader, Writer, ReadCodec, WriteCodec, Transform, E>(
+    (mut reader, mut read_codec): (Reader, ReadCodec),
+    (mut writer, mut write_codec): (Writer, WriteCodec),
+    mut transform: Transform,
+    read_error: &'static str,
+    write_error: &'static str,
+) -> Result<(), LayerError<E>>
+where
+    Reader: AsyncRead + Unpin,
+    Writer: AsyncWrite + Unpin,
+    ReadCodec: FrameCodec,
+    WriteCodec: FrameCodec,
+    Transform: ByteTransform,
+{
+    // Bytes that have been read but do not make up a whole frame yet
+    let mut pending = Vec::new();
+    let mut chunk = [0u8; MAX_BUF_SIZE];
+    loop {
+        let sz = reader.read(&mut chunk).await.map_err(|source| LayerError::Io { context: read_error, source })?;
+        if sz == 0 {
+            break;
+        }
+        pending.extend_from_slice(&chunk[..sz]);
+        // A single read can hold any number of frames, including none at all
+        while let Some(frame) = read_codec.decode(&mut pending).map_err(LayerError::Framing)? {
+            let bytes = write_codec.encode(transform.transform(frame)).map_err(LayerError::Framing)?;
+            if !write_frame(&mut writer, &bytes, write_error).await? {
+                return Ok(());
+            }
+        }
+    }
+    if let Some(frame) = read_codec.decode_eof(&mut pending).map_err(LayerError::Framing)? {
+        let bytes = write_codec.encode(transform.transform(frame)).map_err(LayerError::Framing)?;
+        if !write_frame(&mut writer, &bytes, write_error).await? {
+            return Ok(());
+        }
+    }
+    match writer.shutdown().await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(source) => Err(LayerError::Io { context: write_error, source }),
+    }
+}
+
+/// Writes a whole frame, returning false if the other side has gone away
+pub(crate) async fn write_frame<Writer, E>(writer: &mut Writer, bytes: &[u8], write_error: &'static str) -> Result<bool, LayerError<E>>
+where
+    Writer: AsyncWrite + Unpin,
+{
+    match writer.write_all(bytes).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(false),
+        Err(source) => Err(LayerError::Io { context: write_error, source }),
+    }
+}
+
 /// I/O Pattern Layer takes a (read, write) (as it would for servers) and will also send down
-/// a (read, write) pair (as you would do for clients)
-#[derive(Default)]
-pub struct IoPatternLayer {}
+/// a (read, write) pair (as you would do for clients).
+/// The bytes are transformed on their way through, separately in each direction. By default they
+/// are reversed both ways, but any [ByteTransform] can be plugged in with [IoPatternLayer::new].
+/// Transforms see one frame at a time. Without framing, a frame is whatever a single read returned,
+/// so a [FrameCodec] should be set with [IoPatternLayer::with_framing] whenever messages can be
+/// split across reads or merged into one.
+#[derive(Clone)]
+pub struct IoPatternLayer<Upstream = Reverse, Downstream = Reverse, OuterCodec = Raw, InnerCodec = Raw> {
+    upstream: Upstream,
+    downstream: Downstream,
+    outer_codec: OuterCodec,
+    inner_codec: InnerCodec,
+    timeout: Option<Duration>,
+}
+
+// Only the default transforms have a default, which lets `IoPatternLayer::default()` be inferred
+impl Default for IoPatternLayer {
+    fn default() -> Self {
+        IoPatternLayer::new(Reverse, Reverse)
+    }
+}
 
-impl<InnerService> Layer<InnerService> for IoPatternLayer
+impl<Upstream, Downstream> IoPatternLayer<Upstream, Downstream>
 where
-    InnerService: Service<(ReadHalf<SimplexStream>, WriteHalf<SimplexStream>), Response=()> + Clone + Send + 'static,
+    Upstream: ByteTransform,
+    Downstream: ByteTransform,
+{
+    /// `upstream` applies to what the caller sends down, and `downstream` to what comes back up
+    pub fn new(upstream: Upstream, downstream: Downstream) -> Self {
+        IoPatternLayer { upstream, downstream, outer_codec: Raw, inner_codec: Raw, timeout: None }
+    }
+}
+
+impl<Upstream, Downstream, OuterCodec, InnerCodec> IoPatternLayer<Upstream, Downstream, OuterCodec, InnerCodec>
+where
+    Upstream: ByteTransform,
+    Downstream: ByteTransform,
+    OuterCodec: FrameCodec,
+    InnerCodec: FrameCodec,
+{
+    /// `outer` frames the traffic with the caller, and `inner` the traffic with the inner service.
+    /// Using different codecs translates between the two framings.
+    pub fn with_framing<NewOuter, NewInner>(self, outer: NewOuter, inner: NewInner) -> IoPatternLayer<Upstream, Downstream, NewOuter, NewInner>
+    where
+        NewOuter: FrameCodec,
+        NewInner: FrameCodec,
+    {
+        IoPatternLayer {
+            upstream: self.upstream,
+            downstream: self.downstream,
+            outer_codec: outer,
+            inner_codec: inner,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Limits how long each session may take. A session still going after `timeout` is stopped,
+    /// along with its inner service, and fails with [LayerError::Timeout].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<InnerService, Upstream, Downstream, OuterCodec, InnerCodec> Layer<InnerService> for IoPatternLayer<Upstream, Downstream, OuterCodec, InnerCodec>
+where
+    Upstream: ByteTransform,
+    Downstream: ByteTransform,
+    OuterCodec: FrameCodec,
+    InnerCodec: FrameCodec,
+    InnerService: Service<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), Response=()> + Clone + Send + 'static,
     InnerService::Future: Future<Output=Result<InnerService::Response, InnerService::Error>> + Send + 'static,
     InnerService::Error: Send + 'static,
 {
-    type Service = IoPatternService<InnerService>;
+    type Service = IoPatternService<InnerService, Upstream, Downstream, OuterCodec, InnerCodec>;
 
     fn layer(&self, inner: InnerService) -> Self::Service {
-        IoPatternService { inner }
+        IoPatternService {
+            inner,
+            upstream: self.upstream.clone(),
+            downstream: self.downstream.clone(),
+            outer_codec: self.outer_codec.clone(),
+            inner_codec: self.inner_codec.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::framing::{FrameCodec, FrameError, LengthDelimited, NewlineDelimited};
+    use crate::pattern_io::IoPatternLayer;
+    use crate::transform::{ByteTransform, Identity, Reverse, RunningChecksum, XorMask};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+    use tower::{service_fn, Layer, Service, ServiceBuilder};
+
+    type Pipe = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+    /// Echoes every chunk until EOF, then half-closes its side
+    async fn echo((mut reader, mut writer): Pipe) -> Result<(), &'static str> {
+        let mut buffer = [0u8; 1024];
+        loop {
+            let sz = reader.read(&mut buffer).await.map_err(|_| "read failed")?;
+            if sz == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..sz]).await.map_err(|_| "write failed")?;
+        }
+        writer.shutdown().await.map_err(|_| "shutdown failed")
+    }
+
+    /// A reader that fails straight away, like a connection that was reset
+    struct BrokenReader;
+
+    impl AsyncRead for BrokenReader {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_message_session() {
+        let mut service = IoPatternLayer::default().layer(service_fn(echo));
+        let (caller, layer) = duplex(1024);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer)));
+
+        let mut buffer = [0u8; 1024];
+        for message in ["first", "second", "third"] {
+            caller_write.write_all(message.as_bytes()).await.unwrap();
+            // Reversed on the way down and again on the way up
+            let sz = caller_read.read(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..sz], message.as_bytes());
+        }
+        // Our half-close reaches the inner service, and its half-close reaches us
+        caller_write.shutdown().await.unwrap();
+        assert_eq!(caller_read.read(&mut buffer).await.unwrap(), 0);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_caller_closes_early() {
+        let mut service = IoPatternLayer::default().layer(service_fn(echo));
+        let (caller, layer) = duplex(1024);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer)));
+
+        caller_write.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inner_service_closes_early() {
+        // The inner service says goodbye and leaves without reading anything
+        let mut service = IoPatternLayer::default().layer(service_fn(|(_reader, mut writer): Pipe| async move {
+            writer.write_all(b"bye").await.map_err(|_| "write failed")
+        }));
+        let (caller, layer) = duplex(1024);
+        let (mut caller_read, _caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer)));
+
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"eyb");
+        // The session ends even though we never closed our side
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_errors_from_either_side() {
+        let mut failing = IoPatternLayer::default().layer(service_fn(|_: Pipe| async { Err::<(), _>("inner failed") }));
+        let (_caller, layer) = duplex(1024);
+        let result = failing.call(split(layer)).await;
+        assert!(matches!(result, Err(LayerError::InnerError("inner failed"))));
+
+        let mut service = IoPatternLayer::default().layer(service_fn(echo));
+        let (_caller_read, writer) = split(duplex(1024).0);
+        let result = service.call((BrokenReader, writer)).await;
+        let Err(LayerError::Io { context, source }) = result else { panic!("expected an I/O error") };
+        assert_eq!(context, "Failed to read from input reader");
+        assert_eq!(source.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // A panic in the inner service is reported along with its payload
+        let mut panicking = IoPatternLayer::default().layer(service_fn(|_: Pipe| async { panic!("inner panicked") }));
+        let (_caller, layer) = duplex(1024);
+        let Err(LayerError::<&str>::TaskJoin(join_error)) = panicking.call(split(layer)).await else { panic!("expected a join error") };
+        assert_eq!(*join_error.into_panic().downcast::<&str>().unwrap(), "inner panicked");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_transforms_apply_per_direction() {
+        // The caller masks its traffic, the layer unmasks it on the way down and masks the reply
+        // on the way up, so the inner service only ever sees plain text
+        let seen = Arc::new(AtomicU32::new(0));
+        let report = seen.clone();
+        let mut service = ServiceBuilder::new()
+            .layer(IoPatternLayer::new(XorMask::new(*b"key"), XorMask::new(*b"key")))
+            .layer(IoPatternLayer::new(RunningChecksum::reporting(move |checksum| report.store(checksum, Ordering::SeqCst)), Identity))
+            .service(service_fn(echo));
+        let (caller, layer_side) = duplex(1024);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer_side)));
+
+        let mut mask = XorMask::new(*b"key");
+        caller_write.write_all(&mask.transform(b"plain text".to_vec())).await.unwrap();
+        caller_write.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert_eq!(XorMask::new(*b"key").transform(response), b"plain text");
+        task.await.unwrap().unwrap();
+
+        let mut expected = RunningChecksum::default();
+        expected.transform(b"plain text".to_vec());
+        assert_eq!(seen.load(Ordering::SeqCst), expected.checksum());
+    }
+
+    #[tokio::test]
+    async fn test_transforms_see_whole_frames() {
+        // The caller speaks length-delimited frames, the inner service speaks lines
+        let layer = IoPatternLayer::new(Reverse, Reverse).with_framing(LengthDelimited::new(4096), NewlineDelimited::new(4096));
+        let mut service = layer.layer(service_fn(echo));
+        let (caller, layer_side) = duplex(64);
+        let (mut caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer_side)));
+
+        // Frames larger than a single read, and several frames in one write
+        let long = vec![b'x'; 1500].into_iter().chain(*b"end").collect::<Vec<u8>>();
+        let mut codec = LengthDelimited::new(4096);
+        let mut bytes = codec.encode(long.clone()).unwrap();
+        bytes.extend(codec.encode(b"ab".to_vec()).unwrap());
+        bytes.extend(codec.encode(b"cd".to_vec()).unwrap());
+        let writer = tokio::spawn(async move {
+            caller_write.write_all(&bytes).await.unwrap();
+            caller_write.shutdown().await.unwrap();
+        });
+
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        writer.await.unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut response).unwrap() {
+            frames.push(frame);
+        }
+        // Each frame was reversed as a whole on the way down and again on the way up
+        assert_eq!(frames, [long, b"ab".to_vec(), b"cd".to_vec()]);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_a_typed_error() {
+        let layer = IoPatternLayer::new(Identity, Identity).with_framing(NewlineDelimited::new(8), NewlineDelimited::new(8));
+        let mut service = layer.layer(service_fn(echo));
+        let (caller, layer_side) = duplex(1024);
+        let (_caller_read, mut caller_write) = split(caller);
+        let task = tokio::spawn(service.call(split(layer_side)));
+
+        caller_write.write_all(b"this line is far too long\n").await.unwrap();
+        let result = task.await.unwrap();
+        assert!(matches!(result, Err(LayerError::Framing(FrameError::TooLarge { max: 8, .. }))));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_stops_the_session() {
+        let timeout = Duration::from_millis(20);
+        let mut service = IoPatternLayer::default()
+            .with_timeout(timeout)
+            .layer(service_fn(|_: Pipe| std::future::pending::<Result<(), &'static str>>()));
+        let (caller, layer_side) = duplex(1024);
+        let (mut caller_read, _caller_write) = split(caller);
+        let result = service.call(split(layer_side)).await;
+        assert!(matches!(result, Err(LayerError::Timeout(d)) if d == timeout));
+        // The caller is told the session is over
+        let mut response = Vec::new();
+        caller_read.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::error::LayerError;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::{JoinError, JoinHandle};
+
+/// Drives both directions of a session and the inner service until the session is over.
+/// `upstream` carries what the caller sends, and `downstream` what goes back to the caller.
+/// A session still going after `timeout` is stopped, and fails with [LayerError::Timeout].
+pub(crate) async fn run_session<Upstream, Downstream, E>(mut task: JoinHandle<Result<(), E>>, upstream: Upstream, downstream: Downstream, timeout: Option<Duration>) -> Result<(), LayerError<E>>
+where
+    Upstream: Future<Output=Result<(), LayerError<E>>>,
+    Downstream: Future<Output=Result<(), LayerError<E>>>,
+{
+    let limit = timeout.unwrap_or_default();
+    let deadline = tokio::time::sleep(limit);
+    tokio::pin!(upstream, downstream, deadline);
+    let (mut upstream_done, mut downstream_done) = (false, false);
+    let mut task_result = None;
+    let outcome = loop {
+        tokio::select! {
+            result = &mut upstream, if !upstream_done => match result {
+                Ok(()) => upstream_done = true,
+                Err(e) => break Err(e),
+            },
+            result = &mut downstream, if !downstream_done => match result {
+                Ok(()) => downstream_done = true,
+                Err(e) => break Err(e),
+            },
+            result = &mut task, if task_result.is_none() => {
+                task_result = Some(result);
+            }
+            _ = &mut deadline, if timeout.is_some() => break Err(LayerError::Timeout(limit)),
+        }
+        // Once the inner service is done and everything it sent has been passed on, nobody is
+        // left to receive what the caller sends
+        if downstream_done {
+            if let Some(result) = task_result.take() {
+                break Ok(result);
+            }
+        }
+    };
+    match outcome {
+        Ok(task_result) => task_result
+            .map_err(LayerError::TaskJoin)?
+            .map_err(LayerError::InnerError),
+        Err(failure) => Err(stop_task(task, task_result, failure).await),
+    }
+}
+
+/// Ends a session that failed while relaying or ran out of time, stopping the inner service rather
+/// than leaving it to run on its own. If the inner service had already failed or panicked, that is
+/// most likely why the session failed, so it is reported instead of `failure`.
+async fn stop_task<E>(task: JoinHandle<Result<(), E>>, task_result: Option<Result<Result<(), E>, JoinError>>, failure: LayerError<E>) -> LayerError<E> {
+    let task_result = match task_result {
+        Some(task_result) => task_result,
+        None => {
+            task.abort();
+            task.await
+        }
+    };
+    match task_result {
+        Ok(Err(e)) => LayerError::InnerError(e),
+        Err(e) if e.is_panic() => LayerError::TaskJoin(e),
+        // It finished without a problem, or we cancelled it
+        Ok(Ok(())) | Err(_) => failure,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::LayerError;
+    use crate::session::run_session;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_relay_error_stops_the_inner_service() {
+        // The inner service would run forever, and tells us when it is dropped
+        let (alive, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let _alive = alive;
+            std::future::pending::<Result<(), ()>>().await
+        });
+        let result = run_session(task, async { Err(LayerError::ChannelClosed) }, std::future::pending(), None).await;
+        assert!(matches!(result, Err(LayerError::ChannelClosed)));
+        assert!(stopped.await.is_err());
+
+        // An inner service that failed first is the more useful error
+        let task = tokio::spawn(async { Err::<(), _>("inner failed") });
+        let relay = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err(LayerError::ChannelClosed)
+        };
+        let result = run_session(task, relay, std::future::pending(), None).await;
+        assert!(matches!(result, Err(LayerError::InnerError("inner failed"))));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_stops_the_inner_service() {
+        let (alive, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let _alive = alive;
+            std::future::pending::<Result<(), ()>>().await
+        });
+        let timeout = Duration::from_millis(10);
+        let result = run_session(task, async { Ok(()) }, std::future::pending(), Some(timeout)).await;
+        assert!(matches!(result, Err(LayerError::Timeout(d)) if d == timeout));
+        assert!(stopped.await.is_err());
+    }
+}
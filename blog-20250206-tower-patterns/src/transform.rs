@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+/// A transformation of the bytes flowing through the IO pattern layer in one direction.
+/// The layer clones its transforms for every call, so each session starts from the state the
+/// transform had when it was configured, and can then keep state between chunks.
+pub trait ByteTransform: Clone + Send + 'static {
+    fn transform(&mut self, data: Vec<u8>) -> Vec<u8>;
+}
+
+/// Closures work too, and whatever they capture is cloned into every session with them
+impl<F> ByteTransform for F
+where
+    F: FnMut(Vec<u8>) -> Vec<u8> + Clone + Send + 'static,
+{
+    fn transform(&mut self, data: Vec<u8>) -> Vec<u8> {
+        self(data)
+    }
+}
+
+/// Passes the bytes through untouched
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl ByteTransform for Identity {
+    fn transform(&mut self, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
+}
+
+/// Reverses the bytes. The layer uses this in both directions by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reverse;
+
+impl ByteTransform for Reverse {
+    fn transform(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        data.reverse();
+        data
+    }
+}
+
+/// XORs the bytes with a repeating key.
+/// The position in the key carries over between chunks, so the result does not depend on how the
+/// stream happened to be split. Applying the same mask twice gives back the original bytes.
+#[derive(Clone, Debug)]
+pub struct XorMask {
+    key: Vec<u8>,
+    position: usize,
+}
+
+impl XorMask {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        let key = key.into();
+        assert!(!key.is_empty(), "an XOR mask needs at least one byte of key");
+        XorMask { key, position: 0 }
+    }
+}
+
+impl ByteTransform for XorMask {
+    fn transform(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        for byte in data.iter_mut() {
+            *byte ^= self.key[self.position];
+            self.position = (self.position + 1) % self.key.len();
+        }
+        data
+    }
+}
+
+/// Passes the bytes through untouched, keeping an Adler-32 checksum of everything that went past.
+/// Like any other transform state, the checksum starts over for every session. To find out what it
+/// came to, use [RunningChecksum::reporting], which is told the session's checksum after every chunk.
+#[derive(Clone)]
+pub struct RunningChecksum {
+    checksum: u32,
+    report: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+}
+
+impl Default for RunningChecksum {
+    fn default() -> Self {
+        // Adler-32 starts from 1, not 0
+        RunningChecksum { checksum: 1, report: None }
+    }
+}
+
+impl RunningChecksum {
+    const MODULUS: u32 = 65521;
+
+    /// Calls `report` with the checksum so far after every chunk.
+    /// Every session calls the same `report`, each with its own checksum.
+    pub fn reporting(report: impl Fn(u32) + Send + Sync + 'static) -> Self {
+        RunningChecksum { report: Some(Arc::new(report)), ..Default::default() }
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+}
+
+impl ByteTransform for RunningChecksum {
+    fn transform(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let (mut a, mut b) = (self.checksum & 0xffff, self.checksum >> 16);
+        for byte in &data {
+            a = (a + *byte as u32) % Self::MODULUS;
+            b = (b + a) % Self::MODULUS;
+        }
+        self.checksum = (b << 16) | a;
+        if let Some(report) = &self.report {
+            report(self.checksum);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transform::{ByteTransform, Identity, Reverse, RunningChecksum, XorMask};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_built_in_transforms() {
+        assert_eq!(Identity.transform(b"abc".to_vec()), b"abc");
+        assert_eq!(Reverse.transform(b"abc".to_vec()), b"cba");
+
+        // The key position carries across chunks, so splitting the input changes nothing
+        let mut whole = XorMask::new([0x0f, 0xf0]);
+        let mut split = whole.clone();
+        let masked = whole.transform(b"hello".to_vec());
+        let mut split_masked = split.transform(b"hel".to_vec());
+        split_masked.extend(split.transform(b"lo".to_vec()));
+        assert_eq!(masked, split_masked);
+        assert_eq!(XorMask::new([0x0f, 0xf0]).transform(masked), b"hello");
+
+        // The well-known Adler-32 of "Wikipedia", fed in two chunks
+        let mut checksum = RunningChecksum::default();
+        let fresh = checksum.clone();
+        assert_eq!(checksum.transform(b"Wiki".to_vec()), b"Wiki");
+        checksum.transform(b"pedia".to_vec());
+        assert_eq!(checksum.checksum(), 0x11E60398);
+        // A clone taken before has its own state
+        assert_eq!(fresh.checksum(), 1);
+
+        let reported = Arc::new(AtomicU32::new(0));
+        let report = reported.clone();
+        let mut reporting = RunningChecksum::reporting(move |sum| report.store(sum, Ordering::SeqCst));
+        reporting.transform(b"Wikipedia".to_vec());
+        assert_eq!(reported.load(Ordering::SeqCst), 0x11E60398);
+
+        let mut upper = |data: Vec<u8>| data.to_ascii_uppercase();
+        assert_eq!(upper.transform(b"abc".to_vec()), b"ABC");
+    }
+}